@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, sync::atomic::Ordering, time::Duration};
 
 use anyhow::{Context, Result};
 use model::{
@@ -6,17 +6,25 @@ use model::{
         hydra_message::{HydraData, HydraEventMessage},
         state::HydraNodesState,
     },
+    idempotency::IdempotencyStore,
+    leaderboard::{EventWindow, LeaderboardIndex, Leaderboards, Metric},
     node::Node,
+    player::Player,
+    player_directory::PlayerDirectory,
+    tx_builder::Era,
 };
-use rocket::{http::Method, routes};
+use rocket::http::Method;
 use rocket_cors::{AllowedOrigins, CorsOptions};
-use routes::{global::global, head::head, heads::heads, new_game::new_game};
+use routes::{games::NewGameRequest, new_game::NewGameResponse, openapi::ApiDoc};
 use serde::Deserialize;
 use tokio::{
     spawn,
     sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::interval,
 };
-use tracing::{info, warn};
+use tracing::{debug, warn};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
 mod model;
 mod routes;
@@ -26,6 +34,12 @@ pub const SCRIPT_ADDRESS: &str = "addr_test1wrs939u7ve2yqpflwgvf8r5mlh0fmfx6stk9
 pub const SCRIPT_CBOR: &str = "59038e010000323232323232232323232232253330094a229309b2b19299980418020008a99980598051baa00214985854ccc020c0140044c8c94ccc034c03c0084c926330080012533300b3007300c375400226464646464646464646464646464646464646464a66604460480042930b1bad30220013022002375a604000260400046eb4c078004c078008dd6980e000980e0011bad301a001301a002375a603000260300046eb4c058004c058008dd6980a000980a0011bad30120013012002375a6020002601a6ea80045858dd6180680098051baa0021630083754002646464a666010600860126ea801c4c8c8c8c8c8c8c8c8c8c8c8c8c8c94ccc064c06c0084c8c8c8c8c8c926533301b3017301c375400c26464646464646464a66604c60500042930b19299981318128008a999811981018120008a5115333023301f302400114a02c2c6ea8c098004c098008dd6981200098120011bad30220013022002375a6040002603a6ea801858cc06001c8dd68009980b8041180a000a99980c180a180c9baa00913232323232323232323253330253027002132323232498c080018c07c01cc074020c94ccc08cc07c00454ccc098c094dd50050a4c2c2a66604660400022a66604c604a6ea802852616153330233370e90020008a99981318129baa00a14985858c08cdd50048b1bad302500130250023023001302300230210013021002301f001301f002301d001301a37540122c6020014601e0162c603200260320046eb0c05c004c05c008dd6180a800980a80118098009809801180880098088011807800980780119299980698060008a999805180398058008a511533300a3006300b00114a02c2c6ea8c034004c028dd50038b1192999804980280089919299980718080010a4c2c6eb8c038004c02cdd50010a999804980300089919299980718080010a4c2c6eb8c038004c02cdd50010b18049baa00125333007300330083754002264646464a66601c6020004264932999805980398061baa003132323232323253330143016002149858dd6980a000980a0011bad30120013012002375a6020002601a6ea800c5858dd698070009807001180600098049baa00116253330063002300737540022646464646464a66601e60220042930b1bad300f001300f002375a601a002601a0046eb4c02c004c020dd50008b1b8748000dc3a400444646600200200644a66601200229309919801801980600118019805000ab9a5573aaae7955cfaba157441";
 pub struct MyState {
     state: HydraNodesState,
+    /// Games created through `POST /games`, by idempotency key
+    games: IdempotencyStore<NewGameRequest, NewGameResponse>,
+    /// Players' addresses and display names, by payment key hash
+    players: PlayerDirectory,
+    /// Every node's leaderboards, merged by the maintenance sweep
+    leaderboards: LeaderboardIndex,
 }
 
 #[allow(dead_code)]
@@ -36,6 +50,62 @@ struct Config {
     hosts: Vec<HostConfig>,
     #[serde(default = "default_nodes")]
     nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    socket: SocketConfig,
+    #[serde(default = "default_submission_timeout_ms")]
+    submission_timeout_ms: u64,
+    #[serde(default = "default_reconcile_interval_secs")]
+    reconcile_interval_secs: u64,
+    #[serde(default)]
+    hydra_versions: VersionRange,
+    #[serde(default = "default_initialization_timeout_secs")]
+    initialization_timeout_secs: u64,
+    /// The default expiry policy; `max_session_secs` defaults to `ttl_minutes`
+    #[serde(default)]
+    expiry: ExpiryPolicy,
+    /// Expiry policies for nodes in a region, overriding `expiry`
+    #[serde(default)]
+    region_expiry: HashMap<String, ExpiryPolicy>,
+    #[serde(default = "default_sweep_interval_secs")]
+    sweep_interval_secs: u64,
+    #[serde(default = "default_pending_transaction_ttl_secs")]
+    pending_transaction_ttl_secs: u64,
+    #[serde(default)]
+    leaderboards: LeaderboardConfig,
+    /// Where players' addresses and display names are saved; kept in memory if unset
+    players_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// The expiry policy for `node`: its own, else its region's, else the default
+    fn expiry_policy(&self, node: &NodeConfig) -> ExpiryPolicy {
+        let mut policy = node
+            .expiry
+            .or_else(|| self.region_expiry.get(&node.region).copied())
+            .unwrap_or(self.expiry);
+        policy.max_session_secs.get_or_insert(self.ttl_minutes * 60);
+        policy
+    }
+}
+
+fn default_submission_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_reconcile_interval_secs() -> u64 {
+    60
+}
+
+fn default_initialization_timeout_secs() -> u64 {
+    900
+}
+
+fn default_sweep_interval_secs() -> u64 {
+    5
+}
+
+fn default_pending_transaction_ttl_secs() -> u64 {
+    600
 }
 
 fn default_nodes() -> Vec<NodeConfig> {
@@ -61,6 +131,10 @@ struct HostConfig {
     admin_key_file: PathBuf,
     persisted: bool,
     reserved: bool,
+    expiry: Option<ExpiryPolicy>,
+    era: Option<Era>,
+    #[serde(default)]
+    peers: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -78,6 +152,191 @@ struct NodeConfig {
     admin_key_file: PathBuf,
     persisted: bool,
     reserved: bool,
+    expiry: Option<ExpiryPolicy>,
+    /// The ledger era to build transactions for; by default it's read from the hydra-node version
+    era: Option<Era>,
+    /// The other parties' hydra-nodes, as named in `PeerConnected`
+    #[serde(default)]
+    peers: Vec<String>,
+}
+
+/// How the control plane (re)connects to and talks with hydra-nodes
+#[derive(Debug, Deserialize, Clone)]
+struct SocketConfig {
+    #[serde(default = "default_initial_backoff_ms")]
+    initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    max_backoff_ms: u64,
+    #[serde(default = "default_connect_timeout_ms")]
+    connect_timeout_ms: u64,
+    #[serde(default = "default_send_timeout_ms")]
+    send_timeout_ms: u64,
+    #[serde(default = "default_ping_interval_ms")]
+    ping_interval_ms: u64,
+    #[serde(default = "default_stale_timeout_ms")]
+    stale_timeout_ms: u64,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+            send_timeout_ms: default_send_timeout_ms(),
+            ping_interval_ms: default_ping_interval_ms(),
+            stale_timeout_ms: default_stale_timeout_ms(),
+        }
+    }
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_send_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_ping_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_stale_timeout_ms() -> u64 {
+    30_000
+}
+
+/// Which leaderboards each node keeps, and how long they are
+#[derive(Debug, Deserialize, Clone)]
+struct LeaderboardConfig {
+    #[serde(default = "default_leaderboard_size")]
+    size: usize,
+    #[serde(default = "Metric::all")]
+    metrics: Vec<Metric>,
+    /// An event to keep separate leaderboards for, alongside the all-time and daily ones
+    event: Option<EventWindow>,
+    /// How many players' scores each daily and event board keeps; the worst scores short
+    /// of the board are dropped past this
+    #[serde(default = "default_leaderboard_max_scores")]
+    max_scores: usize,
+}
+
+impl Default for LeaderboardConfig {
+    fn default() -> Self {
+        LeaderboardConfig {
+            size: default_leaderboard_size(),
+            metrics: Metric::all(),
+            event: None,
+            max_scores: default_leaderboard_max_scores(),
+        }
+    }
+}
+
+fn default_leaderboard_size() -> usize {
+    10
+}
+
+fn default_leaderboard_max_scores() -> usize {
+    10_000
+}
+
+/// The hydra-node versions games may be placed on; `min` is inclusive, `max` exclusive
+#[derive(Debug, Deserialize, Clone, Default)]
+struct VersionRange {
+    min: Option<String>,
+    max: Option<String>,
+}
+
+impl VersionRange {
+    /// Whether `version` (ie. "0.19.0-1ffe7c6b") is within the range
+    fn contains(&self, version: &str) -> bool {
+        let version = match parse_version(version) {
+            Some(version) => version,
+            // We can't judge a version we can't read, so only accept it if there are no bounds
+            None => return self.min.is_none() && self.max.is_none(),
+        };
+        let above_min = self
+            .min
+            .as_deref()
+            .and_then(parse_version)
+            .is_none_or(|min| version >= min);
+        let below_max = self
+            .max
+            .as_deref()
+            .and_then(parse_version)
+            .is_none_or(|max| version < max);
+        above_min && below_max
+    }
+}
+
+/// When a player's game is over
+#[derive(Debug, Deserialize, Clone, Copy)]
+struct ExpiryPolicy {
+    /// How long a player may go without moving and still count as active
+    #[serde(default = "default_idle_timeout_secs")]
+    idle_timeout_secs: u64,
+    /// How long after going idle a player may pick their game back up before it is ended
+    #[serde(default = "default_reconnect_grace_secs")]
+    reconnect_grace_secs: u64,
+    /// The longest a game may last, however active the player is
+    max_session_secs: Option<u64>,
+}
+
+impl Default for ExpiryPolicy {
+    fn default() -> Self {
+        ExpiryPolicy {
+            idle_timeout_secs: default_idle_timeout_secs(),
+            reconnect_grace_secs: default_reconnect_grace_secs(),
+            max_session_secs: None,
+        }
+    }
+}
+
+impl ExpiryPolicy {
+    /// Whether `player` is playing right now
+    fn is_active(&self, player: &Player) -> bool {
+        player
+            .idle_for()
+            .is_some_and(|idle| idle <= Duration::from_secs(self.idle_timeout_secs))
+    }
+
+    /// Whether `player`'s game is over, and their slot can be reclaimed
+    fn is_expired(&self, player: &Player) -> bool {
+        let Some(idle) = player.idle_for() else {
+            // They haven't started playing yet
+            return false;
+        };
+        let too_long = self
+            .max_session_secs
+            .is_some_and(|max| player.session_length() > Duration::from_secs(max));
+        too_long || idle > Duration::from_secs(self.idle_timeout_secs + self.reconnect_grace_secs)
+    }
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    5
+}
+
+fn default_reconnect_grace_secs() -> u64 {
+    25
+}
+
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.split('-').next()?;
+    let mut parts = version.split('.').map(|part| part.parse::<u64>().ok());
+    Some((
+        parts.next()??,
+        parts.next().unwrap_or(Some(0))?,
+        parts.next().unwrap_or(Some(0))?,
+    ))
 }
 
 fn default_start_port() -> u32 {
@@ -103,14 +362,14 @@ async fn main() -> Result<()> {
 
     let mut nodes = vec![];
     for node in &config.nodes {
-        let node = Node::try_new(node, &tx)
+        let node = Node::try_new(node, &config, &tx)
             .await
             .context("failed to construct new node")?;
         nodes.push(node);
     }
     for host in &config.hosts {
         for port in host.start_port..=host.end_port {
-            let config = NodeConfig {
+            let node_config = NodeConfig {
                 local_url: host.local_url.clone(),
                 remote_url: host.remote_url.clone(),
                 region: host.region.clone(),
@@ -123,8 +382,11 @@ async fn main() -> Result<()> {
                 max_players: host.max_players,
                 persisted: host.persisted,
                 reserved: host.reserved,
+                expiry: host.expiry,
+                era: host.era,
+                peers: host.peers.clone(),
             };
-            let node = Node::try_new(&config, &tx)
+            let node = Node::try_new(&node_config, &config, &tx)
                 .await
                 .context("failed to construct new node")?;
             nodes.push(node);
@@ -132,12 +394,33 @@ async fn main() -> Result<()> {
     }
 
     let hydra_state = HydraNodesState::from_nodes(nodes);
+    let players = PlayerDirectory::load(config.players_file.clone())
+        .context("failed to load players file")?;
 
     let hydra_state_clone = hydra_state.clone();
     spawn(async move {
         update(hydra_state_clone, rx).await;
     });
 
+    let hydra_state_clone = hydra_state.clone();
+    let leaderboards = LeaderboardIndex::default();
+    let leaderboards_clone = leaderboards.clone();
+    let sweep_interval = Duration::from_secs(config.sweep_interval_secs);
+    spawn(async move {
+        maintain(hydra_state_clone, leaderboards_clone, sweep_interval).await;
+    });
+
+    let players_clone = players.clone();
+    spawn(async move {
+        save_players(players_clone, sweep_interval).await;
+    });
+
+    let hydra_state_clone = hydra_state.clone();
+    let reconcile_interval = Duration::from_secs(config.reconcile_interval_secs);
+    spawn(async move {
+        reconcile(hydra_state_clone, reconcile_interval).await;
+    });
+
     let cors = CorsOptions::default()
         .allowed_origins(AllowedOrigins::all())
         .allowed_methods(
@@ -149,8 +432,17 @@ async fn main() -> Result<()> {
         .allow_credentials(true);
 
     let _rocket = rocket::build()
-        .manage(MyState { state: hydra_state })
-        .mount("/", routes![new_game, heads, head, global])
+        .manage(MyState {
+            state: hydra_state,
+            games: IdempotencyStore::default(),
+            players,
+            leaderboards,
+        })
+        .mount("/", routes::all())
+        .mount(
+            "/",
+            SwaggerUi::new("/swagger-ui/<_..>").url("/openapi.json", ApiDoc::openapi()),
+        )
         .attach(cors.to_cors().unwrap())
         .launch()
         .await?;
@@ -162,41 +454,103 @@ async fn update(state: HydraNodesState, mut rx: UnboundedReceiver<HydraData>) {
     loop {
         match rx.recv().await {
             Some(HydraData::Received { message, authority }) => {
-                let mut state_guard = state.state.write().await;
-                let nodes = &mut state_guard.nodes;
-                let node = nodes
-                    .iter_mut()
-                    .find(|n| n.local_connection.to_authority() == authority);
-                if node.is_none() {
-                    warn!("Node not found: {}", authority);
-                    continue;
+                let handle = match state.find_by_authority(&authority).await {
+                    Some(handle) => handle,
+                    None => {
+                        warn!("Node not found: {}", authority);
+                        continue;
+                    }
+                };
+                let mut guard = handle.write().await;
+                let node = &mut *guard;
+                if let Some(seq) = message.seq() {
+                    if !node.event_seq.track(seq, message.head_started()) {
+                        // Peers are reset on every connection, and caught up from the replay
+                        match message {
+                            HydraEventMessage::PeerConnected(peer) => {
+                                node.update_peer(peer.peer, true, peer.timestamp)
+                            }
+                            HydraEventMessage::PeerDisconnected(peer) => {
+                                node.update_peer(peer.peer, false, peer.timestamp)
+                            }
+                            _ => debug!(
+                                "skipping already processed event {} from {}",
+                                seq, authority
+                            ),
+                        }
+                        continue;
+                    }
                 }
-                let node = node.unwrap();
                 match message {
-                    HydraEventMessage::HeadIsOpen(head_is_open) if node.head_id.is_none() => {
-                        info!(
-                            "updating node {:?} with head_id {:?}",
-                            node.local_connection.to_authority(),
-                            head_is_open.head_id
-                        );
-                        node.head_id = Some(head_is_open.head_id.to_string());
+                    HydraEventMessage::Greetings(greetings) => {
+                        node.event_seq.greeted(greetings.head_id.as_deref());
+                        node.greet(greetings);
+                        if node.verify_script_ref(true) {
+                            spawn(Node::deploy_script_ref(handle.clone()));
+                        }
+                    }
+                    HydraEventMessage::PeerConnected(peer_connected) => {
+                        node.peer_connected(peer_connected)
+                    }
+                    HydraEventMessage::PeerDisconnected(peer_disconnected) => {
+                        node.peer_disconnected(peer_disconnected)
+                    }
+                    HydraEventMessage::HeadIsInitializing(head_is_initializing) => {
+                        node.head_initializing(head_is_initializing)
+                    }
+                    HydraEventMessage::Committed(committed) => node.committed(committed),
+                    HydraEventMessage::HeadIsOpen(head_is_open) => {
+                        node.head_opened(head_is_open);
+                        if node.verify_script_ref(true) {
+                            spawn(Node::deploy_script_ref(handle.clone()));
+                        }
                     }
                     HydraEventMessage::SnapshotConfirmed(snapshot_confirmed) => {
+                        node.decode_failures.record(
+                            &authority,
+                            "SnapshotConfirmed",
+                            &snapshot_confirmed.skipped,
+                        );
+                        node.submissions
+                            .snapshot_confirmed(&snapshot_confirmed.confirmed_transactions);
+                        node.utxo_cache.snapshot(
+                            snapshot_confirmed.utxo,
+                            &snapshot_confirmed.confirmed_transactions,
+                        );
+                        if node.verify_script_ref(false) {
+                            spawn(Node::deploy_script_ref(handle.clone()));
+                        }
+                        node.snapshot_processed();
                         node.stats.calculate_stats(
                             snapshot_confirmed.confirmed_transactions,
                             node.stats_file.clone(),
                         );
                     }
 
-                    HydraEventMessage::TxValid(tx) => match node.add_transaction(tx) {
-                        Ok(_) => {}
-                        Err(e) => {
+                    HydraEventMessage::TxValid(tx) => {
+                        node.submissions.tx_valid(&tx.tx_id);
+                        if let Err(e) = node.add_transaction(tx) {
                             warn!("failed to add transaction {:?}", e);
                         }
-                    },
+                    }
+                    HydraEventMessage::TxInvalid(tx) => {
+                        warn!(
+                            "transaction {} rejected by {}: {}",
+                            hex::encode(&tx.tx_id),
+                            authority,
+                            tx.reason
+                        );
+                        node.submissions.tx_invalid(&tx.tx_id, &tx.reason);
+                        node.utxo_cache.discard(&tx.tx_id);
+                    }
                     _ => {}
                 }
             }
+            Some(HydraData::Connected { authority }) => {
+                if let Some(handle) = state.find_by_authority(&authority).await {
+                    handle.write().await.connected();
+                }
+            }
             Some(HydraData::Send(_)) => {}
             None => {
                 warn!("mpsc disconnected");
@@ -205,3 +559,71 @@ async fn update(state: HydraNodesState, mut rx: UnboundedReceiver<HydraData>) {
         }
     }
 }
+
+/// Housekeeping that shouldn't depend on new games being placed: ends expired games and
+/// reclaims their UTxOs, drops transactions that will never be confirmed, saves the stats
+/// that changed because of either, and rebuilds the merged leaderboards
+async fn maintain(state: HydraNodesState, leaderboards: LeaderboardIndex, period: Duration) {
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        let mut regions: HashMap<String, Leaderboards> = HashMap::new();
+        for handle in state.nodes.iter() {
+            let mut node = handle.write().await;
+            let expired = node.cleanup_players();
+            node.stats.leaderboards.roll();
+            regions
+                .entry(node.region.clone())
+                .or_default()
+                .merge(&node.stats.leaderboards);
+            let ttl = node.pending_ttl;
+            let pruned = node.stats.prune_pending(ttl);
+            if expired.is_empty() && pruned == 0 {
+                continue;
+            }
+
+            debug!(
+                "expired {} games and pruned {} pending transactions on {}",
+                expired.len(),
+                pruned,
+                node.local_connection.to_authority()
+            );
+            node.stats.save(node.stats_file.clone());
+            if !expired.is_empty() {
+                spawn(Node::reclaim_games(handle.clone(), expired));
+            }
+        }
+        leaderboards.rebuild(regions);
+    }
+}
+
+/// Saves changes to the player directory every `period`, so requests don't wait on the disk
+async fn save_players(players: PlayerDirectory, period: Duration) {
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        players.save().await;
+    }
+}
+
+/// Periodically (and right after startup) rebuilds each node's players from its UTxO set
+async fn reconcile(state: HydraNodesState, period: Duration) {
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        for handle in state.nodes.iter() {
+            let client = handle.read().await.client();
+            if !client.socket.online.load(Ordering::SeqCst) {
+                continue;
+            }
+            match client.fetch_utxos().await {
+                Ok(utxos) => handle.write().await.reconcile(&utxos),
+                Err(e) => warn!(
+                    "failed to fetch utxos from {} for reconciliation: {:?}",
+                    client.local_connection.to_authority(),
+                    e
+                ),
+            }
+        }
+    }
+}
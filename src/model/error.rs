@@ -0,0 +1,192 @@
+use std::{error::Error, fmt};
+
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+    Request, Response,
+};
+use serde::Serialize;
+use tracing::error;
+use utoipa::ToSchema;
+
+/// Errors the control plane reports to its clients.
+///
+/// Raised as `anyhow` errors deep in `Node` and `TxBuilder`, and recovered with
+/// `From<anyhow::Error>` at the route; anything else becomes `Internal`.
+#[derive(Clone, Debug)]
+pub enum ControlPlaneError {
+    /// No node is available to place a game on
+    NoCapacity,
+    InvalidAddress(String),
+    /// The node's admin has no UTxO left to fund a transaction with
+    NoAdminUtxo,
+    HeadOffline(String),
+    TxRejected(String),
+    SubmissionTimeout(String),
+    /// The validator's reference script isn't deployed in the head
+    ScriptRefMissing,
+    /// No game is running for the given player
+    GameNotFound(String),
+    /// The request didn't carry the game's `end_token`
+    NotGameOwner(String),
+    /// An `Idempotency-Key` was reused for a different request
+    IdempotencyConflict(String),
+    InvalidDisplayName(String),
+    /// Another player already goes by the display name
+    DisplayNameTaken(String),
+    /// The display name wasn't signed for by the player's key
+    DisplayNameUnauthorized(String),
+    /// The player isn't a valid address, known display name or payment key hash
+    PlayerNotFound(String),
+    /// Anything unexpected; what went wrong is logged rather than sent to the client
+    Internal,
+}
+
+/// The body of every error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    #[schema(example = "no_capacity")]
+    code: &'static str,
+    message: String,
+}
+
+impl ControlPlaneError {
+    /// A stable identifier for the error, for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ControlPlaneError::NoCapacity => "no_capacity",
+            ControlPlaneError::InvalidAddress(_) => "invalid_address",
+            ControlPlaneError::NoAdminUtxo => "no_admin_utxo",
+            ControlPlaneError::HeadOffline(_) => "head_offline",
+            ControlPlaneError::TxRejected(_) => "tx_rejected",
+            ControlPlaneError::SubmissionTimeout(_) => "submission_timeout",
+            ControlPlaneError::ScriptRefMissing => "script_ref_missing",
+            ControlPlaneError::GameNotFound(_) => "game_not_found",
+            ControlPlaneError::NotGameOwner(_) => "not_game_owner",
+            ControlPlaneError::IdempotencyConflict(_) => "idempotency_conflict",
+            ControlPlaneError::InvalidDisplayName(_) => "invalid_display_name",
+            ControlPlaneError::DisplayNameTaken(_) => "display_name_taken",
+            ControlPlaneError::DisplayNameUnauthorized(_) => "display_name_unauthorized",
+            ControlPlaneError::PlayerNotFound(_) => "player_not_found",
+            ControlPlaneError::Internal => "internal",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            ControlPlaneError::InvalidAddress(_) | ControlPlaneError::InvalidDisplayName(_) => {
+                Status::BadRequest
+            }
+            ControlPlaneError::GameNotFound(_) | ControlPlaneError::PlayerNotFound(_) => {
+                Status::NotFound
+            }
+            ControlPlaneError::NotGameOwner(_) | ControlPlaneError::DisplayNameUnauthorized(_) => {
+                Status::Forbidden
+            }
+            ControlPlaneError::DisplayNameTaken(_) => Status::Conflict,
+            ControlPlaneError::TxRejected(_) | ControlPlaneError::IdempotencyConflict(_) => {
+                Status::UnprocessableEntity
+            }
+            ControlPlaneError::SubmissionTimeout(_) => Status::GatewayTimeout,
+            ControlPlaneError::Internal => Status::InternalServerError,
+            ControlPlaneError::NoCapacity
+            | ControlPlaneError::NoAdminUtxo
+            | ControlPlaneError::HeadOffline(_)
+            | ControlPlaneError::ScriptRefMissing => Status::ServiceUnavailable,
+        }
+    }
+}
+
+impl Error for ControlPlaneError {}
+
+impl fmt::Display for ControlPlaneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlPlaneError::NoCapacity => write!(f, "no nodes available"),
+            ControlPlaneError::InvalidAddress(address) => write!(f, "invalid address: {address}"),
+            ControlPlaneError::NoAdminUtxo => write!(f, "no admin UTxOs found"),
+            ControlPlaneError::HeadOffline(node) => write!(f, "not connected to {node}"),
+            ControlPlaneError::TxRejected(reason) => {
+                write!(f, "transaction rejected by the head: {reason}")
+            }
+            ControlPlaneError::SubmissionTimeout(details) => write!(f, "timed out {details}"),
+            ControlPlaneError::ScriptRefMissing => write!(f, "script ref not available"),
+            ControlPlaneError::GameNotFound(game) => write!(f, "no game running for {game}"),
+            ControlPlaneError::NotGameOwner(game) => {
+                write!(f, "missing or wrong end token for the game of {game}")
+            }
+            ControlPlaneError::IdempotencyConflict(key) => {
+                write!(f, "idempotency key {key} was used for a different request")
+            }
+            ControlPlaneError::InvalidDisplayName(name) => write!(
+                f,
+                "invalid display name: {name}; use 3 to 24 letters, digits, '_' or '-'"
+            ),
+            ControlPlaneError::DisplayNameTaken(name) => {
+                write!(f, "display name {name} is already taken")
+            }
+            ControlPlaneError::DisplayNameUnauthorized(name) => write!(
+                f,
+                "display name {name} needs a signature by the key behind the player's address"
+            ),
+            ControlPlaneError::PlayerNotFound(player) => write!(f, "unknown player {player}"),
+            ControlPlaneError::Internal => write!(f, "internal error"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ControlPlaneError {
+    fn from(error: anyhow::Error) -> Self {
+        // Context may have been added on the way up, so look through the whole chain
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<ControlPlaneError>())
+            .cloned()
+            .unwrap_or_else(|| {
+                // The chain may name nodes, files and such, so it stays in our logs
+                error!("internal error: {:#}", error);
+                ControlPlaneError::Internal
+            })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ControlPlaneError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let body = Json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+        });
+        Response::build_from(body.respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn known_errors_are_found_under_context() {
+        let error: anyhow::Error = ControlPlaneError::NoAdminUtxo.into();
+        let error = ControlPlaneError::from(error.context("placing game"));
+
+        assert_eq!(error.code(), "no_admin_utxo");
+    }
+
+    #[test]
+    fn internal_errors_dont_leak_their_details() {
+        let error = Err::<(), _>(anyhow!("connection refused"))
+            .context("fetching UTxOs from http://10.0.0.7:4001")
+            .unwrap_err();
+        let error = ControlPlaneError::from(error);
+
+        assert_eq!(error.code(), "internal");
+        assert_eq!(error.status(), Status::InternalServerError);
+        assert_eq!(error.to_string(), "internal error");
+    }
+}
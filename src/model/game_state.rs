@@ -62,19 +62,7 @@ pub enum PlayerState {
 
 impl From<GameState> for PlutusData {
     fn from(val: GameState) -> Self {
-        let is_over = if val.is_over {
-            PlutusData::Constr(Constr {
-                tag: 121,
-                any_constructor: Some(1),
-                fields: vec![],
-            })
-        } else {
-            PlutusData::Constr(Constr {
-                tag: 121,
-                any_constructor: Some(0),
-                fields: vec![],
-            })
-        };
+        let is_over = constr(val.is_over as u64, vec![]);
 
         let owner_bytes: alonzo::BoundedBytes = val.owner.into();
         let owner = PlutusData::Constr(Constr {
@@ -120,15 +108,19 @@ impl TryFrom<PlutusData> for GameState {
     fn try_from(value: PlutusData) -> Result<Self, Self::Error> {
         match value {
             PlutusData::Constr(constr) => {
+                if constr.fields.len() != 7 {
+                    bail!("Invalid number of fields");
+                }
+
                 let is_over = match constr.fields[0].clone() {
-                    PlutusData::Constr(constr) => constr.tag == 122,
+                    PlutusData::Constr(constr) => constructor_index(&constr) == Some(1),
                     _ => bail!("Invalid is_over"),
                 };
 
                 let owner: Vec<u8> = match constr.fields[1].clone() {
                     PlutusData::Constr(constr) => {
-                        let owner_bytes = match constr.fields[0].clone() {
-                            PlutusData::BoundedBytes(bytes) => bytes,
+                        let owner_bytes = match constr.fields.first() {
+                            Some(PlutusData::BoundedBytes(bytes)) => bytes.clone(),
                             _ => bail!("Invalid owner bytes"),
                         };
 
@@ -139,8 +131,8 @@ impl TryFrom<PlutusData> for GameState {
 
                 let admin: Vec<u8> = match constr.fields[2].clone() {
                     PlutusData::Constr(constr) => {
-                        let admin_bytes = match constr.fields[0].clone() {
-                            PlutusData::BoundedBytes(bytes) => bytes,
+                        let admin_bytes = match constr.fields.first() {
+                            Some(PlutusData::BoundedBytes(bytes)) => bytes.clone(),
                             _ => bail!("Invalid admin bytes"),
                         };
 
@@ -205,6 +197,31 @@ impl TryFrom<PlutusData> for GameState {
     }
 }
 
+/// Plutus data for constructor `index` of a data type, tagged the way the ledger expects:
+/// `any_constructor` is only read for the catch-all tag 102
+pub fn constr(index: u64, fields: Vec<PlutusData>) -> PlutusData {
+    let (tag, any_constructor) = match index {
+        0..=6 => (121 + index, None),
+        7..=127 => (1280 + index - 7, None),
+        _ => (102, Some(index)),
+    };
+    PlutusData::Constr(Constr {
+        tag,
+        any_constructor,
+        fields,
+    })
+}
+
+/// Which constructor `constr` is, reading its tag the way `constr` writes it
+pub fn constructor_index(constr: &Constr<PlutusData>) -> Option<u64> {
+    match constr.tag {
+        121..=127 => Some(constr.tag - 121),
+        1280..=1400 => Some(constr.tag - 1280 + 7),
+        102 => constr.any_constructor,
+        _ => None,
+    }
+}
+
 impl GameState {
     pub fn new(owner: Vec<u8>, admin: Vec<u8>) -> GameState {
         GameState {
@@ -480,24 +497,12 @@ impl TryFrom<PlutusData> for Position {
 
 impl From<PlayerState> for PlutusData {
     fn from(val: PlayerState) -> Self {
-        PlutusData::Constr(match val {
-            PlayerState::Live => Constr {
-                tag: 121,
-                any_constructor: Some(0),
-                fields: vec![],
-            },
-            PlayerState::Dead => Constr {
-                tag: 121,
-                any_constructor: Some(1),
-                fields: vec![],
-            },
-            // Constr(1, [])
-            PlayerState::Reborn => Constr {
-                tag: 121,
-                any_constructor: Some(2),
-                fields: vec![],
-            }, // Constr(2, [])
-        })
+        let index = match val {
+            PlayerState::Live => 0,
+            PlayerState::Dead => 1,
+            PlayerState::Reborn => 2,
+        };
+        constr(index, vec![])
     }
 }
 
@@ -506,10 +511,10 @@ impl TryFrom<PlutusData> for PlayerState {
 
     fn try_from(value: PlutusData) -> Result<Self, Self::Error> {
         match value {
-            PlutusData::Constr(constr) => match constr.tag {
-                121 => Ok(PlayerState::Live),
-                122 => Ok(PlayerState::Dead),
-                123 => Ok(PlayerState::Reborn),
+            PlutusData::Constr(constr) => match constructor_index(&constr) {
+                Some(0) => Ok(PlayerState::Live),
+                Some(1) => Ok(PlayerState::Dead),
+                Some(2) => Ok(PlayerState::Reborn),
                 _ => Err(anyhow!("Invalid tag for PlayerState")),
             },
             _ => Err(anyhow!("Invalid PlutusData for PlayerState")),
@@ -555,7 +560,7 @@ impl TryFrom<PlutusData> for LevelId {
                 };
 
                 let demo_playback = match fields[3].clone() {
-                    PlutusData::Constr(constr) => constr.tag == 122,
+                    PlutusData::Constr(constr) => constructor_index(&constr) == Some(1),
                     _ => bail!("Invalid demoplayback"),
                 };
 
@@ -580,12 +585,82 @@ impl From<LevelId> for PlutusData {
                 PlutusData::BigInt(alonzo::BigInt::Int(val.map.into())),
                 PlutusData::BigInt(alonzo::BigInt::Int(val.skill.into())),
                 PlutusData::BigInt(alonzo::BigInt::Int(val.episode.into())),
-                PlutusData::Constr(Constr {
-                    tag: 121,
-                    any_constructor: Some(if val.demo_playback { 1 } else { 0 }),
-                    fields: vec![],
-                }),
+                constr(val.demo_playback as u64, vec![]),
             ],
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished_game() -> GameState {
+        let mut state = GameState::new(vec![0xa1; 28], vec![0xad; 28]);
+        state.is_over = true;
+        state.player.player_state = PlayerState::Dead;
+        state.player.total_stats.kill_count = 12;
+        state.leveltime = vec![1234, 5678];
+        state.level.demo_playback = true;
+        state
+    }
+
+    #[test]
+    fn constructor_tags() {
+        for (index, tag, any_constructor) in [
+            (0, 121, None),
+            (1, 122, None),
+            (6, 127, None),
+            (7, 1280, None),
+            (127, 1400, None),
+            (128, 102, Some(128)),
+        ] {
+            match constr(index, vec![]) {
+                PlutusData::Constr(constr) => {
+                    assert_eq!(constr.tag, tag);
+                    assert_eq!(constr.any_constructor, any_constructor);
+                    assert_eq!(constructor_index(&constr), Some(index));
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn finished_game_round_trips() {
+        let state = GameState::try_from(PlutusData::from(finished_game())).unwrap();
+
+        assert!(state.is_over);
+        assert!(matches!(state.player.player_state, PlayerState::Dead));
+        assert_eq!(state.player.total_stats.kill_count, 12);
+        assert_eq!(state.leveltime, vec![1234, 5678]);
+        assert!(state.level.demo_playback);
+        assert_eq!(state.owner, vec![0xa1; 28]);
+        assert_eq!(state.admin, vec![0xad; 28]);
+    }
+
+    #[test]
+    fn new_game_isnt_over() {
+        let state = GameState::new(vec![0xa1; 28], vec![0xad; 28]);
+        let state = GameState::try_from(PlutusData::from(state)).unwrap();
+
+        assert!(!state.is_over);
+        assert!(matches!(state.player.player_state, PlayerState::Live));
+        assert!(!state.level.demo_playback);
+    }
+
+    #[test]
+    fn missing_fields_are_an_error() {
+        let fields = match PlutusData::from(finished_game()) {
+            PlutusData::Constr(constr) => constr.fields,
+            _ => unreachable!(),
+        };
+
+        let truncated = constr(0, fields[..3].to_vec());
+        assert!(GameState::try_from(truncated).is_err());
+
+        let mut no_owner = fields.clone();
+        no_owner[1] = constr(0, vec![]);
+        assert!(GameState::try_from(constr(0, no_owner)).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use serde::{Serialize, Serializer};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use super::utxo::DecodeFailure;
+
+/// How many skipped entries we keep around to report
+const RECENT_FAILURES: usize = 50;
+
+/// UTxO set entries a node reported that we couldn't decode, and skipped.
+///
+/// Shared between the node and its clients, since sets fetched over http are decoded without
+/// the node lock.
+#[derive(Clone, Default)]
+pub struct DecodeFailures(Arc<Mutex<FailureLog>>);
+
+#[derive(Default)]
+struct FailureLog {
+    total: u64,
+    recent: VecDeque<RecordedFailure>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct RecordedFailure {
+    /// The event or endpoint the set came from
+    source: String,
+    utxo: String,
+    error: String,
+    at: String,
+}
+
+/// How `DecodeFailures` is reported
+#[derive(Serialize, ToSchema)]
+pub struct FailureReport {
+    total: u64,
+    recent: Vec<RecordedFailure>,
+}
+
+impl DecodeFailures {
+    pub fn record(&self, authority: &str, source: &str, skipped: &[DecodeFailure]) {
+        if skipped.is_empty() {
+            return;
+        }
+
+        let at = Utc::now().to_rfc3339();
+        let mut log = self.0.lock().expect("decode failures lock poisoned");
+        for failure in skipped {
+            warn!(
+                "skipped utxo {} in {} from {}: {}",
+                failure.utxo, source, authority, failure.error
+            );
+            log.total += 1;
+            if log.recent.len() == RECENT_FAILURES {
+                log.recent.pop_front();
+            }
+            log.recent.push_back(RecordedFailure {
+                source: source.to_string(),
+                utxo: failure.utxo.clone(),
+                error: failure.error.clone(),
+                at: at.clone(),
+            });
+        }
+    }
+}
+
+impl Serialize for DecodeFailures {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let report = {
+            let log = self.0.lock().expect("decode failures lock poisoned");
+            FailureReport {
+                total: log.total,
+                recent: log.recent.iter().cloned().collect(),
+            }
+        };
+        report.serialize(serializer)
+    }
+}
@@ -0,0 +1,177 @@
+use tracing::warn;
+
+/// Tracks how far into a hydra-node's event history we are, so the history replayed on
+/// every connection is only processed once.
+///
+/// Sequence numbers only mean something for one run of one hydra-node: they start over
+/// when its persistence is wiped, and a new head may come with a new history. On each
+/// connection the node replays its history and then sends Greetings, so a replay that
+/// ends short of what we've already processed, or Greetings for a head other than ours,
+/// means the history was reset; so does a live event going backwards.
+#[derive(Clone, Debug, Default)]
+pub struct EventSeq {
+    /// The head `last` belongs to
+    head_id: Option<String>,
+    /// The highest seq processed
+    last: Option<u64>,
+    /// The highest seq replayed on the current connection, ie. before its Greetings
+    replayed: Option<u64>,
+    /// Whether the current connection's Greetings has arrived, so events are live
+    greeted: bool,
+}
+
+impl EventSeq {
+    /// Picks up from `seq` of `head_id`, ie. as saved with the stats
+    pub fn resume(head_id: Option<String>, seq: Option<u64>) -> Self {
+        EventSeq {
+            head_id,
+            last: seq,
+            ..Default::default()
+        }
+    }
+
+    pub fn head_id(&self) -> Option<&str> {
+        self.head_id.as_deref()
+    }
+
+    /// A new connection, whose history is replayed before its Greetings
+    pub fn connected(&mut self) {
+        self.replayed = None;
+        self.greeted = false;
+    }
+
+    /// Whether the event `seq` should be processed; `head_id` is the head the event
+    /// initializes or opens, if it does
+    pub fn track(&mut self, seq: u64, head_id: Option<&str>) -> bool {
+        if !self.greeted {
+            self.replayed = self.replayed.max(Some(seq));
+        }
+        let already_processed = self.last.is_some_and(|last| seq <= last);
+        if already_processed {
+            // While replaying, this is just history we've seen before
+            if !self.greeted {
+                return false;
+            }
+            warn!(
+                "event {} arrived after {}, the node's history must have been reset",
+                seq,
+                self.last.unwrap_or_default()
+            );
+        }
+        if head_id.is_some() {
+            self.head_id = head_id.map(str::to_string);
+        }
+        self.last = Some(seq);
+        true
+    }
+
+    /// Greetings ends the replay; `head_id` is the head it reports, if any
+    pub fn greeted(&mut self, head_id: Option<&str>) {
+        self.greeted = true;
+        let replay_short = self.last.is_some() && self.replayed < self.last;
+        let head_changed = head_id.is_some() && head_id != self.head_id.as_deref();
+        if replay_short || head_changed {
+            warn!(
+                "node's history was reset (replayed up to {:?}, we had {:?} of head {:?}); continuing from there",
+                self.replayed, self.last, self.head_id
+            );
+            self.last = self.replayed;
+            self.head_id = head_id.map(str::to_string);
+        }
+    }
+
+    /// Whether the current connection's replay is over, ie. events reflect the head as it
+    /// is now rather than as it was
+    pub fn is_greeted(&self) -> bool {
+        self.greeted
+    }
+
+    /// The highest seq processed
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replays `history` on a new connection, returning the seqs that were processed
+    fn replay(
+        seqs: &mut EventSeq,
+        history: &[(u64, Option<&str>)],
+        greeting: Option<&str>,
+    ) -> Vec<u64> {
+        seqs.connected();
+        let processed = history
+            .iter()
+            .filter(|(seq, head_id)| seqs.track(*seq, *head_id))
+            .map(|(seq, _)| *seq)
+            .collect();
+        seqs.greeted(greeting);
+        processed
+    }
+
+    #[test]
+    fn replayed_history_is_skipped() {
+        let mut seqs = EventSeq::default();
+        let history = [(0, Some("a")), (1, None), (2, None)];
+        assert_eq!(replay(&mut seqs, &history, Some("a")), vec![0, 1, 2]);
+
+        assert_eq!(replay(&mut seqs, &history, Some("a")), Vec::<u64>::new());
+        assert!(seqs.is_greeted());
+        assert!(seqs.track(3, None));
+        assert_eq!(seqs.last(), Some(3));
+    }
+
+    #[test]
+    fn replay_lasts_until_greeted() {
+        let mut seqs = EventSeq::default();
+        assert!(!seqs.is_greeted());
+        replay(&mut seqs, &[(0, Some("a"))], Some("a"));
+        assert!(seqs.is_greeted());
+
+        seqs.connected();
+        assert!(!seqs.is_greeted());
+    }
+
+    #[test]
+    fn history_resumed_from_a_snapshot_replays_the_rest() {
+        let mut seqs = EventSeq::resume(Some("a".to_string()), Some(1));
+        let history = [(0, Some("a")), (1, None), (2, None)];
+        assert_eq!(replay(&mut seqs, &history, Some("a")), vec![2]);
+    }
+
+    #[test]
+    fn wiped_history_starts_over() {
+        let mut seqs = EventSeq::resume(Some("a".to_string()), Some(40));
+        assert_eq!(
+            replay(&mut seqs, &[(0, None), (1, None)], None),
+            Vec::<u64>::new()
+        );
+
+        assert_eq!(seqs.last(), Some(1));
+        assert!(seqs.track(2, Some("b")));
+        assert_eq!(seqs.head_id(), Some("b"));
+    }
+
+    #[test]
+    fn greeting_for_another_head_starts_over() {
+        let mut seqs = EventSeq::resume(Some("a".to_string()), Some(3));
+        let history = [(0, None), (1, Some("b")), (2, None), (3, None)];
+        assert_eq!(replay(&mut seqs, &history, Some("b")), Vec::<u64>::new());
+
+        assert_eq!(seqs.head_id(), Some("b"));
+        assert!(seqs.track(4, None));
+    }
+
+    #[test]
+    fn live_seq_going_backwards_is_a_reset() {
+        let mut seqs = EventSeq::default();
+        replay(&mut seqs, &[(0, Some("a")), (1, None)], Some("a"));
+
+        assert!(seqs.track(0, Some("b")));
+        assert_eq!(seqs.head_id(), Some("b"));
+        assert!(seqs.track(1, None));
+    }
+}
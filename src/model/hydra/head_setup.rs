@@ -0,0 +1,231 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use utoipa::ToSchema;
+
+use super::messages::{committed::Committed, head_is_initializing::HeadIsInitializing};
+
+/// Progress of a head from `HeadIsInitializing` until it opens
+#[derive(Clone, Debug)]
+pub struct HeadSetup {
+    /// Hydra verification keys of every party, hex encoded
+    parties: Vec<String>,
+    /// The UTxOs each party committed, keyed by party
+    commits: HashMap<String, Vec<String>>,
+    initializing_since: DateTime<Utc>,
+    opened_at: Option<DateTime<Utc>>,
+    /// How long a head may take to open before we consider it stuck
+    timeout: Duration,
+}
+
+/// How `HeadSetup` is reported
+#[derive(Serialize, ToSchema)]
+pub struct HeadSetupReport {
+    parties: Vec<String>,
+    commits: HashMap<String, Vec<String>>,
+    pending_commits: Vec<String>,
+    initializing_since: String,
+    opened_at: Option<String>,
+    initialization_secs: i64,
+    stuck: bool,
+}
+
+impl HeadSetup {
+    pub fn new(head_is_initializing: &HeadIsInitializing, timeout: Duration) -> Self {
+        HeadSetup {
+            parties: head_is_initializing
+                .parties
+                .iter()
+                .map(hex::encode)
+                .collect(),
+            commits: HashMap::new(),
+            initializing_since: head_is_initializing
+                .timestamp
+                .parse::<DateTime<Utc>>()
+                .unwrap_or_else(|_| Utc::now()),
+            opened_at: None,
+            timeout,
+        }
+    }
+
+    pub fn party_count(&self) -> usize {
+        self.parties.len()
+    }
+
+    pub fn committed(&mut self, committed: &Committed) {
+        self.commits.insert(
+            hex::encode(&committed.party),
+            committed
+                .utxos
+                .iter()
+                .map(|utxo| utxo.to_string())
+                .collect(),
+        );
+    }
+
+    pub fn opened(&mut self, timestamp: &str) {
+        self.opened_at = Some(
+            timestamp
+                .parse::<DateTime<Utc>>()
+                .unwrap_or_else(|_| Utc::now()),
+        );
+    }
+
+    fn duration(&self) -> chrono::Duration {
+        self.opened_at.unwrap_or_else(Utc::now) - self.initializing_since
+    }
+
+    /// Still initializing, and taking longer than it should
+    pub fn is_stuck(&self) -> bool {
+        self.opened_at.is_none()
+            && self
+                .duration()
+                .to_std()
+                .is_ok_and(|duration| duration > self.timeout)
+    }
+}
+
+impl Serialize for HeadSetup {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        HeadSetupReport {
+            parties: self.parties.clone(),
+            commits: self.commits.clone(),
+            pending_commits: self
+                .parties
+                .iter()
+                .filter(|party| !self.commits.contains_key(*party))
+                .cloned()
+                .collect(),
+            initializing_since: self.initializing_since.to_rfc3339(),
+            opened_at: self.opened_at.map(|opened_at| opened_at.to_rfc3339()),
+            initialization_secs: self.duration().num_seconds(),
+            stuck: self.is_stuck(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::SCRIPT_ADDRESS;
+
+    const ALICE: &str = "a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1";
+    const BOB: &str = "b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0";
+    const CAROL: &str = "c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0";
+
+    fn ago(secs: i64) -> String {
+        (Utc::now() - chrono::Duration::seconds(secs)).to_rfc3339()
+    }
+
+    fn setup(timestamp: &str, timeout_secs: u64) -> HeadSetup {
+        let head_is_initializing = HeadIsInitializing::try_from(json!({
+            "headId": "head",
+            "parties": [{ "vkey": ALICE }, { "vkey": BOB }, { "vkey": CAROL }],
+            "seq": 1,
+            "timestamp": timestamp,
+        }))
+        .unwrap();
+        HeadSetup::new(&head_is_initializing, Duration::from_secs(timeout_secs))
+    }
+
+    fn committed(party: &str, tx_ids: &[&str]) -> Committed {
+        let utxo: serde_json::Map<String, Value> = tx_ids
+            .iter()
+            .map(|tx_id| {
+                (
+                    format!("{}#0", tx_id),
+                    json!({ "address": SCRIPT_ADDRESS, "value": { "lovelace": 5_000_000 } }),
+                )
+            })
+            .collect();
+        Committed::try_from(json!({
+            "headId": "head",
+            "party": { "vkey": party },
+            "seq": 2,
+            "timestamp": ago(0),
+            "utxo": utxo,
+        }))
+        .unwrap()
+    }
+
+    fn report(setup: &HeadSetup) -> Value {
+        serde_json::to_value(setup).unwrap()
+    }
+
+    #[test]
+    fn tracks_commits_until_every_party_has_committed() {
+        let mut setup = setup(&ago(10), 60);
+        assert_eq!(setup.party_count(), 3);
+        assert_eq!(
+            report(&setup)["pending_commits"],
+            json!([ALICE, BOB, CAROL])
+        );
+
+        let tx_id = "11".repeat(32);
+        setup.committed(&committed(BOB, &[&tx_id]));
+
+        let report = report(&setup);
+        assert_eq!(report["pending_commits"], json!([ALICE, CAROL]));
+        assert_eq!(report["commits"][BOB], json!([format!("{}#0", tx_id)]));
+
+        setup.committed(&committed(ALICE, &[]));
+        setup.committed(&committed(CAROL, &[&"22".repeat(32), &"33".repeat(32)]));
+        let report = serde_json::to_value(&setup).unwrap();
+        assert_eq!(report["pending_commits"], json!([]));
+        // Committing nothing still counts
+        assert_eq!(report["commits"][ALICE], json!([]));
+        assert_eq!(report["commits"][CAROL].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn a_repeated_commit_replaces_the_earlier_one() {
+        let mut setup = setup(&ago(10), 60);
+        setup.committed(&committed(BOB, &[&"11".repeat(32)]));
+        setup.committed(&committed(BOB, &[&"22".repeat(32)]));
+
+        assert_eq!(
+            report(&setup)["commits"][BOB],
+            json!([format!("{}#0", "22".repeat(32))])
+        );
+    }
+
+    #[test]
+    fn stuck_once_initializing_outlasts_the_timeout() {
+        assert!(!setup(&ago(30), 60).is_stuck());
+
+        let setup = setup(&ago(120), 60);
+        assert!(setup.is_stuck());
+        let report = report(&setup);
+        assert_eq!(report["stuck"], json!(true));
+        assert!(report["initialization_secs"].as_i64().unwrap() >= 120);
+        assert_eq!(report["opened_at"], Value::Null);
+    }
+
+    #[test]
+    fn an_open_head_isnt_stuck() {
+        let mut setup = setup(&ago(600), 60);
+        let opened_at = Utc::now() - chrono::Duration::seconds(300);
+        setup.opened(&opened_at.to_rfc3339());
+
+        assert!(!setup.is_stuck());
+        let report = report(&setup);
+        assert_eq!(report["stuck"], json!(false));
+        // Initialization took as long as it took, however long ago that was
+        assert_eq!(report["initialization_secs"], json!(300));
+    }
+
+    #[test]
+    fn unreadable_timestamp_counts_from_now() {
+        let setup = setup("yesterday-ish", 60);
+
+        assert!(!setup.is_stuck());
+        assert!(report(&setup)["initialization_secs"].as_i64().unwrap() < 5);
+    }
+}
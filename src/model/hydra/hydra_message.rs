@@ -7,16 +7,21 @@ use serde_json::Value;
 use super::messages::{
     committed::Committed, greetings::Greetings, head_is_initializing::HeadIsInitializing,
     head_is_open::HeadIsOpen, peer_connected::PeerConnected, peer_disconnected::PeerDisconnected,
-    snapshot_confirmed::SnapshotConfirmed, tx_valid::TxValid,
+    snapshot_confirmed::SnapshotConfirmed, tx_invalid::TxInvalid, tx_valid::TxValid,
 };
 
 pub enum HydraMessage {
     HydraEvent(HydraEventMessage),
     Ping(Vec<u8>),
+    Pong(Vec<u8>),
 }
 
 #[derive(Debug)]
 pub enum HydraData {
+    /// A (re)connection to the node, which replays its history before anything else
+    Connected {
+        authority: String,
+    },
     Received {
         message: HydraEventMessage,
         authority: String,
@@ -29,6 +34,7 @@ pub enum HydraData {
 pub enum HydraEventMessage {
     SnapshotConfirmed(SnapshotConfirmed),
     TxValid(TxValid),
+    TxInvalid(TxInvalid),
     PeerConnected(PeerConnected),
     PeerDisconnected(PeerDisconnected),
     HeadIsInitializing(HeadIsInitializing),
@@ -38,6 +44,34 @@ pub enum HydraEventMessage {
     Unimplemented(Value),
 }
 
+impl HydraEventMessage {
+    /// The sequence number of the event, if it has one
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            HydraEventMessage::SnapshotConfirmed(message) => Some(message.seq),
+            HydraEventMessage::TxValid(message) => Some(message.seq),
+            HydraEventMessage::TxInvalid(message) => Some(message.seq),
+            HydraEventMessage::PeerConnected(message) => Some(message.seq),
+            HydraEventMessage::PeerDisconnected(message) => Some(message.seq),
+            HydraEventMessage::HeadIsInitializing(message) => Some(message.seq),
+            HydraEventMessage::HeadIsOpen(message) => Some(message.seq),
+            HydraEventMessage::Committed(message) => Some(message.seq),
+            // Greetings is sent on every connection and isn't part of the history
+            HydraEventMessage::Greetings(_) => None,
+            HydraEventMessage::Unimplemented(value) => value["seq"].as_u64(),
+        }
+    }
+
+    /// The head the event initializes or opens, if it does
+    pub fn head_started(&self) -> Option<&str> {
+        match self {
+            HydraEventMessage::HeadIsInitializing(message) => Some(&message.head_id),
+            HydraEventMessage::HeadIsOpen(message) => Some(&message.head_id),
+            _ => None,
+        }
+    }
+}
+
 impl TryFrom<Value> for HydraEventMessage {
     type Error = anyhow::Error;
 
@@ -49,6 +83,7 @@ impl TryFrom<Value> for HydraEventMessage {
                 SnapshotConfirmed::try_from(value).map(HydraEventMessage::SnapshotConfirmed)
             }
             "TxValid" => TxValid::try_from(value).map(HydraEventMessage::TxValid),
+            "TxInvalid" => TxInvalid::try_from(value).map(HydraEventMessage::TxInvalid),
             "PeerConnected" => PeerConnected::try_from(value).map(HydraEventMessage::PeerConnected),
             "PeerDisconnected" => {
                 PeerDisconnected::try_from(value).map(HydraEventMessage::PeerDisconnected)
@@ -77,6 +112,7 @@ impl TryFrom<Message> for HydraMessage {
                 Ok(HydraMessage::HydraEvent(event))
             }
             Message::Ping(payload) => Ok(HydraMessage::Ping(payload)),
+            Message::Pong(payload) => Ok(HydraMessage::Pong(payload)),
             _ => Err(HydraMessageError::UnsupportedMessageFormat),
         }
     }
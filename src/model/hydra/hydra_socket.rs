@@ -1,9 +1,13 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_tungstenite::{
     stream::Stream,
     tokio::{connect_async, TokioAdapter},
@@ -14,15 +18,19 @@ use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
+use serde::{Serialize, Serializer};
 use tokio::{
     net::TcpStream,
+    select,
     sync::{mpsc::UnboundedSender, Mutex},
-    task::yield_now,
+    time::{interval, sleep, timeout, MissedTickBehavior},
 };
 use tokio_native_tls::TlsStream;
 use tracing::{debug, warn};
+use utoipa::ToSchema;
 
-use super::hydra_message::{HydraData, HydraMessage};
+use super::hydra_message::{HydraData, HydraMessage, HydraMessageError};
+use crate::{model::error::ControlPlaneError, SocketConfig};
 
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -30,8 +38,10 @@ pub struct HydraSocket {
     url: String,
     identifier: String,
     pub online: Arc<AtomicBool>,
+    pub health: SocketHealth,
     writer: UnboundedSender<HydraData>,
     sender: Arc<Mutex<Option<HydraSender>>>,
+    config: SocketConfig,
 
     suppress_noise: bool,
 }
@@ -47,76 +57,203 @@ pub struct HydraSender {
     sender: HydraSink,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    /// Trying to (re)establish the connection
+    Connecting,
+    /// Connected, and the last interaction succeeded
+    Online,
+    /// Connected, but sending or decoding a message recently failed
+    Degraded,
+    /// Not connected; waiting to retry
+    Offline,
+}
+
+struct HealthStatus {
+    state: HealthState,
+    last_error: Option<String>,
+    last_message: Option<Instant>,
+    last_pong: Option<Instant>,
+}
+
+/// Connection health of a socket, shared between the listener task and everyone reporting on it
+#[derive(Clone)]
+pub struct SocketHealth(Arc<StdMutex<HealthStatus>>);
+
+/// How `SocketHealth` is reported
+#[derive(Serialize, ToSchema)]
+pub struct HealthReport {
+    state: HealthState,
+    last_error: Option<String>,
+    seconds_since_last_message: Option<u64>,
+    seconds_since_last_pong: Option<u64>,
+}
+
 impl HydraSocket {
-    pub fn new(url: &str, identifier: String, writer: &UnboundedSender<HydraData>) -> Self {
+    pub fn new(
+        url: &str,
+        identifier: String,
+        writer: &UnboundedSender<HydraData>,
+        config: &SocketConfig,
+    ) -> Self {
         HydraSocket {
             url: url.to_string(),
             identifier,
             online: Arc::new(AtomicBool::new(false)),
+            health: SocketHealth::new(),
             writer: writer.clone(),
             sender: Arc::new(Mutex::new(None)),
+            config: config.clone(),
 
             suppress_noise: false,
         }
     }
 
     pub async fn send(&self, message: String) -> Result<()> {
-        // If the sender is None, we aren't currently connected, so spin loop until we're reconnected
-        loop {
+        // Fail fast rather than waiting for a reconnect that may never come
+        if !self.online.load(Ordering::SeqCst) {
+            return Err(ControlPlaneError::HeadOffline(self.url.clone()).into());
+        }
+
+        let send_timeout = Duration::from_millis(self.config.send_timeout_ms);
+        let result = timeout(send_timeout, async {
             let mut sender = self.sender.lock().await;
-            if let Some(sender) = sender.as_mut() {
-                return sender.send(HydraData::Send(message)).await;
+            match sender.as_mut() {
+                Some(sender) => sender.send(HydraData::Send(message)).await,
+                None => Err(ControlPlaneError::HeadOffline(self.url.clone()).into()),
             }
-            // Make sure we don't kill the CPU
-            yield_now().await;
+        })
+        .await
+        .context("timed out sending message")
+        .and_then(|result| result);
+
+        if let Err(e) = &result {
+            self.health.degraded(e);
         }
+        result
     }
 
     pub fn listen(&self) {
         let mut socket = self.clone();
         tokio::spawn(async move {
             socket.suppress_noise = false;
+            let mut attempt: u32 = 0;
             loop {
-                match socket.connect_and_listen().await {
+                socket.health.set_state(HealthState::Connecting);
+                match socket.connect_and_listen(&mut attempt).await {
                     Ok(()) => {
                         if !socket.suppress_noise {
                             socket.suppress_noise = true;
                             warn!("Disconnected from {}, reconnecting", socket.url);
                         }
+                        socket.health.offline(None);
                     }
                     Err(e) => {
                         if !socket.suppress_noise {
                             socket.suppress_noise = true;
                             warn!("Error connecting to {}: {}", socket.url, e);
                         }
+                        socket.health.offline(Some(e.to_string()));
                     }
                 }
                 socket.online.store(false, Ordering::SeqCst);
-                yield_now().await;
+                {
+                    let mut sender = socket.sender.lock().await;
+                    *sender = None;
+                }
+
+                let backoff = socket.backoff(attempt);
+                attempt = attempt.saturating_add(1);
+                debug!("Reconnecting to {} in {:?}", socket.url, backoff);
+                sleep(backoff).await;
             }
         });
     }
-    async fn connect_and_listen(&mut self) -> Result<()> {
-        let (ws_stream, _) = connect_async(&self.url).await?;
+
+    async fn connect_and_listen(&mut self, attempt: &mut u32) -> Result<()> {
+        let connect_timeout = Duration::from_millis(self.config.connect_timeout_ms);
+        let (ws_stream, _) = timeout(connect_timeout, connect_async(&self.url))
+            .await
+            .context("timed out connecting")??;
         println!("Succesfully connected to {}", &self.url);
         self.suppress_noise = false;
-        self.online.store(true, Ordering::SeqCst);
+        *attempt = 0;
         let (sender, receiver) = ws_stream.split();
         {
             let mut sender_lock = self.sender.lock().await;
             *sender_lock = Some(HydraSender { sender });
         }
+        self.online.store(true, Ordering::SeqCst);
+        self.health.connected();
+        self.writer.send(HydraData::Connected {
+            authority: self.identifier.clone(),
+        })?;
         self.process_messages(receiver).await?;
         Ok(())
     }
 
+    fn backoff(&self, attempt: u32) -> Duration {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or_default();
+        backoff_delay(
+            self.config.initial_backoff_ms,
+            self.config.max_backoff_ms,
+            attempt,
+            nanos,
+        )
+    }
+
     async fn process_messages(&self, mut receiver: HydraSource) -> Result<()> {
-        while let Some(msg) = receiver.next().await {
-            let msg = msg?;
-            let hydra_message = HydraMessage::try_from(msg.clone())?;
+        let stale_timeout = Duration::from_millis(self.config.stale_timeout_ms);
+        let mut heartbeat = interval(Duration::from_millis(self.config.ping_interval_ms));
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, and we just connected
+        heartbeat.tick().await;
+
+        loop {
+            let msg = select! {
+                msg = receiver.next() => match msg {
+                    Some(msg) => msg?,
+                    None => return Ok(()),
+                },
+                _ = heartbeat.tick() => {
+                    // A half-open connection never errors, it just goes quiet
+                    if let Some(silence) = self.health.stale(stale_timeout) {
+                        self.close().await;
+                        bail!("no traffic from {} for {:?}, connection is stale", self.url, silence);
+                    }
+                    if let Err(e) = self.send_control(Message::Ping(vec![])).await {
+                        warn!("Failed to ping {}: {}", self.url, e);
+                        self.health.degraded(&e);
+                    }
+                    continue;
+                }
+            };
+
+            self.health.message_received();
+            let hydra_message = match HydraMessage::try_from(msg.clone()) {
+                Ok(hydra_message) => hydra_message,
+                Err(HydraMessageError::UnsupportedMessageFormat) => {
+                    return Err(HydraMessageError::UnsupportedMessageFormat.into())
+                }
+                // A single event we can't decode shouldn't cost us the connection
+                Err(e) => {
+                    warn!("Failed to decode message from {}: {}", self.url, e);
+                    self.health.degraded(&e);
+                    continue;
+                }
+            };
             match hydra_message {
                 HydraMessage::Ping(payload) => {
                     debug!("Received ping: {:?}", payload);
+                    self.send_control(Message::Pong(payload)).await?;
+                }
+
+                HydraMessage::Pong(_) => {
+                    self.health.pong_received();
                 }
 
                 HydraMessage::HydraEvent(event) => {
@@ -130,8 +267,46 @@ impl HydraSocket {
                 }
             }
         }
-        Ok(())
     }
+
+    async fn send_control(&self, message: Message) -> Result<()> {
+        let send_timeout = Duration::from_millis(self.config.send_timeout_ms);
+        timeout(send_timeout, async {
+            let mut sender = self.sender.lock().await;
+            match sender.as_mut() {
+                Some(sender) => sender.send_control(message).await,
+                None => Err(anyhow!("not connected to {}", self.url)),
+            }
+        })
+        .await
+        .context("timed out sending control message")?
+    }
+
+    async fn close(&self) {
+        let mut sender = self.sender.lock().await;
+        if let Some(mut sender) = sender.take() {
+            // Best effort; the peer is probably gone anyway
+            let _ = timeout(
+                Duration::from_millis(self.config.send_timeout_ms),
+                sender.sender.close(),
+            )
+            .await;
+        }
+    }
+}
+
+/// Exponential backoff, capped at `max_ms`, with up to 50% jitter (picked by `entropy`) so
+/// many nodes on the same host don't all reconnect in lockstep
+fn backoff_delay(initial_ms: u64, max_ms: u64, attempt: u32, entropy: u64) -> Duration {
+    let base = initial_ms
+        .saturating_mul(1u64 << attempt.min(16))
+        .min(max_ms);
+    let jitter = if base > 1 {
+        entropy % (base / 2 + 1)
+    } else {
+        0
+    };
+    Duration::from_millis(base - jitter)
 }
 
 impl HydraSender {
@@ -145,4 +320,358 @@ impl HydraSender {
             _ => Err(anyhow!("Can only send data of variant Send")),
         }
     }
+
+    pub async fn send_control(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Ping(_) | Message::Pong(_) => {
+                self.sender.send(message).await?;
+                Ok(())
+            }
+            _ => Err(anyhow!("Can only send ping or pong control messages")),
+        }
+    }
+}
+
+impl SocketHealth {
+    fn new() -> Self {
+        SocketHealth(Arc::new(StdMutex::new(HealthStatus {
+            state: HealthState::Offline,
+            last_error: None,
+            last_message: None,
+            last_pong: None,
+        })))
+    }
+
+    fn set_state(&self, state: HealthState) {
+        self.0.lock().expect("health lock poisoned").state = state;
+    }
+
+    fn connected(&self) {
+        let mut status = self.0.lock().expect("health lock poisoned");
+        status.state = HealthState::Online;
+        // Give the heartbeat a fresh window on every connection
+        status.last_message = Some(Instant::now());
+    }
+
+    fn message_received(&self) {
+        let mut status = self.0.lock().expect("health lock poisoned");
+        status.last_message = Some(Instant::now());
+        if status.state == HealthState::Degraded {
+            status.state = HealthState::Online;
+        }
+    }
+
+    fn pong_received(&self) {
+        let mut status = self.0.lock().expect("health lock poisoned");
+        status.last_pong = Some(Instant::now());
+    }
+
+    /// Time since we last heard anything on the connection, pongs included
+    fn since_last_traffic(&self) -> Option<Duration> {
+        let status = self.0.lock().expect("health lock poisoned");
+        [status.last_message, status.last_pong]
+            .into_iter()
+            .flatten()
+            .max()
+            .map(|t| t.elapsed())
+    }
+
+    /// How long the connection has been silent, if that's longer than `timeout`
+    fn stale(&self, timeout: Duration) -> Option<Duration> {
+        self.since_last_traffic()
+            .filter(|silence| *silence > timeout)
+    }
+
+    fn degraded(&self, error: &impl Display) {
+        let mut status = self.0.lock().expect("health lock poisoned");
+        if status.state == HealthState::Online {
+            status.state = HealthState::Degraded;
+        }
+        status.last_error = Some(error.to_string());
+    }
+
+    fn offline(&self, error: Option<String>) {
+        let mut status = self.0.lock().expect("health lock poisoned");
+        status.state = HealthState::Offline;
+        if error.is_some() {
+            status.last_error = error;
+        }
+    }
+}
+
+impl Serialize for SocketHealth {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let report = {
+            let status = self.0.lock().expect("health lock poisoned");
+            HealthReport {
+                state: status.state,
+                last_error: status.last_error.clone(),
+                seconds_since_last_message: status.last_message.map(|t| t.elapsed().as_secs()),
+                seconds_since_last_pong: status.last_pong.map(|t| t.elapsed().as_secs()),
+            }
+        };
+        report.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    fn socket(url: &str, config: SocketConfig) -> HydraSocket {
+        let (writer, _) = mpsc::unbounded_channel();
+        HydraSocket::new(url, url.to_string(), &writer, &config)
+    }
+
+    fn state(health: &SocketHealth) -> HealthState {
+        health.0.lock().unwrap().state
+    }
+
+    fn last_error(health: &SocketHealth) -> Option<String> {
+        health.0.lock().unwrap().last_error.clone()
+    }
+
+    /// Pretends the last message and pong arrived this long ago (kept to a few seconds, as
+    /// `Instant`s can't go back past boot)
+    fn heard_from(health: &SocketHealth, message: Option<Duration>, pong: Option<Duration>) {
+        let mut status = health.0.lock().unwrap();
+        status.last_message = message.map(|ago| Instant::now() - ago);
+        status.last_pong = pong.map(|ago| Instant::now() - ago);
+    }
+
+    #[test]
+    fn backoff_doubles_until_capped() {
+        let delays: Vec<u64> = (0..8)
+            .map(|attempt| backoff_delay(500, 30_000, attempt, 0).as_millis() as u64)
+            .collect();
+
+        assert_eq!(
+            delays,
+            vec![500, 1_000, 2_000, 4_000, 8_000, 16_000, 30_000, 30_000]
+        );
+    }
+
+    #[test]
+    fn backoff_stays_capped_for_any_attempt() {
+        for attempt in [16, 17, 64, u32::MAX] {
+            assert_eq!(
+                backoff_delay(500, 30_000, attempt, 0),
+                Duration::from_millis(30_000)
+            );
+        }
+        // Even when doubling would overflow
+        assert_eq!(
+            backoff_delay(u64::MAX / 2, u64::MAX, u32::MAX, 0),
+            Duration::from_millis(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn jitter_takes_off_at_most_half() {
+        for attempt in 0..10 {
+            let base = backoff_delay(500, 30_000, attempt, 0);
+            let mut seen = std::collections::HashSet::new();
+            for entropy in 0..2_000 {
+                let delay = backoff_delay(500, 30_000, attempt, entropy * 7_919);
+                assert!(delay <= base, "{:?} > {:?}", delay, base);
+                assert!(delay >= base / 2, "{:?} < half of {:?}", delay, base);
+                seen.insert(delay);
+            }
+            // Nodes retrying together should spread out
+            assert!(seen.len() > 100);
+        }
+    }
+
+    #[test]
+    fn no_jitter_on_tiny_backoffs() {
+        assert_eq!(backoff_delay(0, 30_000, 3, 12_345), Duration::ZERO);
+        assert_eq!(backoff_delay(1, 1, 3, 12_345), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn connect_times_out_on_silent_peer() {
+        // Accepts the TCP connection but never answers the websocket handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let mut socket = socket(
+            &url,
+            SocketConfig {
+                connect_timeout_ms: 50,
+                ..SocketConfig::default()
+            },
+        );
+        let mut attempt = 3;
+
+        let started = Instant::now();
+        let error = socket.connect_and_listen(&mut attempt).await.unwrap_err();
+
+        assert!(error.to_string().contains("timed out connecting"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // Only a successful connection resets the backoff
+        assert_eq!(attempt, 3);
+        assert!(!socket.online.load(Ordering::SeqCst));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn send_fails_fast_when_offline() {
+        let socket = socket("ws://127.0.0.1:1", SocketConfig::default());
+
+        let started = Instant::now();
+        let error = socket.send("{}".to_string()).await.unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<ControlPlaneError>(),
+            Some(ControlPlaneError::HeadOffline(_))
+        ));
+        assert!(started.elapsed() < Duration::from_millis(socket.config.send_timeout_ms));
+    }
+
+    #[test]
+    fn health_starts_offline() {
+        let health = SocketHealth::new();
+
+        assert_eq!(state(&health), HealthState::Offline);
+        assert_eq!(health.since_last_traffic(), None);
+    }
+
+    #[test]
+    fn health_goes_online_on_connect() {
+        let health = SocketHealth::new();
+        health.set_state(HealthState::Connecting);
+        assert_eq!(state(&health), HealthState::Connecting);
+
+        health.connected();
+
+        assert_eq!(state(&health), HealthState::Online);
+        assert!(health.since_last_traffic().unwrap() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn errors_degrade_an_online_socket_until_the_next_message() {
+        let health = SocketHealth::new();
+        health.connected();
+
+        health.degraded(&"failed to send");
+        assert_eq!(state(&health), HealthState::Degraded);
+        assert_eq!(last_error(&health).as_deref(), Some("failed to send"));
+
+        health.message_received();
+        assert_eq!(state(&health), HealthState::Online);
+        // The error stays around for whoever's looking into it
+        assert_eq!(last_error(&health).as_deref(), Some("failed to send"));
+    }
+
+    #[test]
+    fn errors_dont_bring_a_disconnected_socket_online() {
+        let health = SocketHealth::new();
+        health.degraded(&"failed to ping");
+        assert_eq!(state(&health), HealthState::Offline);
+
+        health.set_state(HealthState::Connecting);
+        health.degraded(&"failed to ping");
+        assert_eq!(state(&health), HealthState::Connecting);
+
+        health.message_received();
+        assert_eq!(state(&health), HealthState::Connecting);
+    }
+
+    #[test]
+    fn disconnecting_keeps_the_last_error_unless_theres_a_new_one() {
+        let health = SocketHealth::new();
+        health.connected();
+        health.degraded(&"failed to send");
+
+        health.offline(None);
+        assert_eq!(state(&health), HealthState::Offline);
+        assert_eq!(last_error(&health).as_deref(), Some("failed to send"));
+
+        health.offline(Some("connection refused".to_string()));
+        assert_eq!(last_error(&health).as_deref(), Some("connection refused"));
+    }
+
+    #[test]
+    fn silent_connection_is_stale() {
+        let health = SocketHealth::new();
+        let timeout = Duration::from_secs(3);
+
+        heard_from(&health, Some(Duration::from_secs(1)), None);
+        assert_eq!(health.stale(timeout), None);
+
+        heard_from(&health, Some(Duration::from_secs(5)), None);
+        assert!(health.stale(timeout).unwrap() >= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn pongs_keep_a_quiet_connection_fresh() {
+        let health = SocketHealth::new();
+        let timeout = Duration::from_secs(3);
+
+        heard_from(
+            &health,
+            Some(Duration::from_secs(8)),
+            Some(Duration::from_secs(1)),
+        );
+        assert_eq!(health.stale(timeout), None);
+
+        heard_from(
+            &health,
+            Some(Duration::from_secs(8)),
+            Some(Duration::from_secs(6)),
+        );
+        assert!(health.stale(timeout).unwrap() >= Duration::from_secs(6));
+    }
+
+    #[test]
+    fn connecting_gives_a_fresh_heartbeat_window() {
+        let health = SocketHealth::new();
+        heard_from(&health, Some(Duration::from_secs(8)), None);
+        assert!(health.stale(Duration::from_secs(3)).is_some());
+
+        health.connected();
+
+        assert_eq!(health.stale(Duration::from_secs(3)), None);
+    }
+
+    #[test]
+    fn pongs_dont_change_state() {
+        let health = SocketHealth::new();
+        health.connected();
+        health.degraded(&"failed to decode");
+
+        health.pong_received();
+
+        assert_eq!(state(&health), HealthState::Degraded);
+    }
+
+    #[tokio::test]
+    async fn failed_connection_goes_offline_with_its_error() {
+        // Bind and drop a listener so the port refuses connections
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let socket = socket(
+            &url,
+            SocketConfig {
+                initial_backoff_ms: 60_000,
+                max_backoff_ms: 60_000,
+                ..SocketConfig::default()
+            },
+        );
+
+        socket.listen();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while last_error(&socket.health).is_none() && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state(&socket.health), HealthState::Offline);
+        assert!(last_error(&socket.health).is_some());
+        assert!(!socket.online.load(Ordering::SeqCst));
+    }
 }
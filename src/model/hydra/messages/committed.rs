@@ -2,18 +2,20 @@ use anyhow::{Context, Result};
 use derivative::Derivative;
 use serde_json::Value;
 
-use crate::model::hydra::utxo::UTxO;
+use crate::model::hydra::utxo::{DecodeFailure, UTxO, UtxoSet};
 
 #[allow(dead_code)]
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Committed {
-    head_id: String,
+    pub head_id: String,
     #[derivative(Debug(format_with = "crate::model::format_hex"))]
-    party: Vec<u8>,
-    seq: u64,
-    timestamp: String,
-    utxos: Vec<UTxO>,
+    pub party: Vec<u8>,
+    pub seq: u64,
+    pub timestamp: String,
+    pub utxos: Vec<UTxO>,
+    /// Entries of `utxos` we couldn't decode
+    pub skipped: Vec<DecodeFailure>,
 }
 
 impl TryFrom<Value> for Committed {
@@ -29,12 +31,8 @@ impl TryFrom<Value> for Committed {
         let party = hex::decode(party_obj["vkey"].as_str().context("Invalid vkey")?)?;
         let seq = value["seq"].as_u64().context("Invalid seq")?;
         let timestamp = value["timestamp"].as_str().context("Invalid timestamp")?;
-        let utxos = value["utxo"]
-            .as_object()
-            .context("Invalid UTxOs object")?
-            .iter()
-            .map(|(key, value)| UTxO::try_from_value(key, value))
-            .collect::<Result<Vec<UTxO>>>()?;
+        let UtxoSet { utxos, skipped } =
+            UtxoSet::try_from_json(&value["utxo"]).context("Invalid UTxOs object")?;
 
         Ok(Committed {
             head_id: head_id.to_string(),
@@ -42,6 +40,7 @@ impl TryFrom<Value> for Committed {
             seq,
             timestamp: timestamp.to_string(),
             utxos,
+            skipped,
         })
     }
 }
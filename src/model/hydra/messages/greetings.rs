@@ -1,15 +1,19 @@
 use anyhow::{Context, Result};
 use serde_json::Value;
 
-use crate::model::hydra::utxo::UTxO;
+use crate::model::hydra::utxo::{DecodeFailure, UTxO, UtxoSet};
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct Greetings {
-    head_status: String,
-    hydra_node_version: String,
-    me: Vec<u8>,
-    snapshot_utxos: Vec<UTxO>,
+    pub head_status: String,
+    /// The head the node is in, if any
+    pub head_id: Option<String>,
+    pub hydra_node_version: String,
+    pub me: Vec<u8>,
+    pub snapshot_utxos: Vec<UTxO>,
+    /// Entries of `snapshot_utxos` we couldn't decode
+    pub skipped: Vec<DecodeFailure>,
 }
 
 impl TryFrom<Value> for Greetings {
@@ -20,23 +24,29 @@ impl TryFrom<Value> for Greetings {
             .as_str()
             .context("Invalid head_status")?
             .to_owned();
+        let head_id = value["hydraHeadId"].as_str().map(str::to_string);
         let hydra_node_version = value["hydraNodeVersion"]
             .as_str()
             .context("Invalid hydra_node_version")?
             .to_owned();
         let me_obj = value["me"].as_object().context("Invalid me object")?;
         let me = hex::decode(me_obj["vkey"].as_str().context("Invalid me vkey")?)?;
-        let snapshot_utxos = value["snapshotUtxo"]
-            .as_object()
-            .context("Invalid snapshotUtxo object")?
-            .iter()
-            .map(|(key, value)| UTxO::try_from_value(key, value))
-            .collect::<Result<Vec<UTxO>>>()?;
+        // There's no snapshot until the head is open
+        let UtxoSet {
+            utxos: snapshot_utxos,
+            skipped,
+        } = if value["snapshotUtxo"].is_null() {
+            UtxoSet::default()
+        } else {
+            UtxoSet::try_from_json(&value["snapshotUtxo"]).context("Invalid snapshotUtxo")?
+        };
         Ok(Greetings {
             head_status: head_status.to_string(),
+            head_id,
             hydra_node_version: hydra_node_version.to_string(),
             me,
             snapshot_utxos,
+            skipped,
         })
     }
 }
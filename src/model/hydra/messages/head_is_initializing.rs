@@ -4,10 +4,10 @@ use serde_json::Value;
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct HeadIsInitializing {
-    head_id: String,
-    parties: Vec<Vec<u8>>,
-    seq: u64,
-    timestamp: String,
+    pub head_id: String,
+    pub parties: Vec<Vec<u8>>,
+    pub seq: u64,
+    pub timestamp: String,
 }
 
 impl TryFrom<Value> for HeadIsInitializing {
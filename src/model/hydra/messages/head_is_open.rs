@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use serde_json::Value;
 
-use crate::model::hydra::utxo::UTxO;
+use crate::model::hydra::utxo::{DecodeFailure, UTxO, UtxoSet};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -9,6 +9,8 @@ pub struct HeadIsOpen {
     pub head_id: String,
     pub seq: u64,
     pub utxos: Vec<UTxO>,
+    /// Entries of `utxos` we couldn't decode
+    pub skipped: Vec<DecodeFailure>,
     pub timestamp: String,
 }
 
@@ -22,17 +24,14 @@ impl TryFrom<Value> for HeadIsOpen {
             .to_owned();
         let seq = value["seq"].as_u64().context("Invalid seq")?;
         let timestamp = value["timestamp"].as_str().context("Invalid timestamp")?;
-        let utxos = value["utxo"]
-            .as_object()
-            .context("Invalid UTxOs object")?
-            .iter()
-            .map(|(key, value)| UTxO::try_from_value(key, value))
-            .collect::<Result<Vec<UTxO>>>()?;
+        let UtxoSet { utxos, skipped } =
+            UtxoSet::try_from_json(&value["utxo"]).context("Invalid UTxOs object")?;
 
         Ok(HeadIsOpen {
             head_id: head_id.to_string(),
             seq,
             utxos,
+            skipped,
             timestamp: timestamp.to_string(),
         })
     }
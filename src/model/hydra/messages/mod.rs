@@ -6,4 +6,5 @@ pub mod new_tx;
 pub mod peer_connected;
 pub mod peer_disconnected;
 pub mod snapshot_confirmed;
+pub mod tx_invalid;
 pub mod tx_valid;
@@ -1,17 +1,21 @@
 use anyhow::{bail, Result};
 use pallas::txbuilder::BuiltTransaction;
 use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use crate::model::tx_builder::Era;
+
 pub struct NewTx {
     transaction: Transaction,
 }
 
 struct Transaction {
+    era: Era,
     cbor_hex: String,
     tx_id: String,
 }
 
 impl NewTx {
-    pub fn new(tx: BuiltTransaction) -> Result<Self> {
+    pub fn new(tx: BuiltTransaction, era: Era) -> Result<Self> {
         if tx.signatures.is_none() {
             bail!("No signatures");
         }
@@ -19,6 +23,7 @@ impl NewTx {
         Ok(NewTx {
             transaction: {
                 Transaction {
+                    era,
                     cbor_hex: hex::encode(tx.tx_bytes),
                     tx_id: hex::encode(tx.tx_hash.0),
                 }
@@ -45,7 +50,7 @@ impl Serialize for Transaction {
         S: Serializer,
     {
         let mut s = serializer.serialize_struct("transaction", 4)?;
-        s.serialize_field("type", "Witnessed Tx BabbageEra")?;
+        s.serialize_field("type", self.era.tx_type())?;
         s.serialize_field("description", "")?;
         s.serialize_field("cborHex", &self.cbor_hex)?;
         s.serialize_field("txId", &self.tx_id)?;
@@ -4,9 +4,9 @@ use serde_json::Value;
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct PeerConnected {
-    peer: String,
-    timestamp: String,
-    seq: u64,
+    pub peer: String,
+    pub timestamp: String,
+    pub seq: u64,
 }
 
 impl TryFrom<Value> for PeerConnected {
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use serde_json::Value;
 
-use crate::model::hydra::utxo::UTxO;
+use crate::model::hydra::utxo::{DecodeFailure, UTxO, UtxoSet};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -12,6 +12,8 @@ pub struct SnapshotConfirmed {
     pub confirmed_transactions: Vec<Vec<u8>>,
     pub snapshot_number: u64,
     pub utxo: Vec<UTxO>,
+    /// Entries of `utxo` we couldn't decode
+    pub skipped: Vec<DecodeFailure>,
     pub timestamp: String,
 }
 
@@ -47,12 +49,10 @@ impl TryFrom<Value> for SnapshotConfirmed {
         let snapshot_number = snapshot["number"]
             .as_u64()
             .context("Invalid snapshotNumber")?;
-        let utxo = snapshot["utxo"]
-            .as_object()
-            .context("Invalid utxo")?
-            .iter()
-            .map(|(key, value)| UTxO::try_from_value(key, value))
-            .collect::<Result<Vec<UTxO>>>()?;
+        let UtxoSet {
+            utxos: utxo,
+            skipped,
+        } = UtxoSet::try_from_json(&snapshot["utxo"]).context("Invalid utxo")?;
         let timestamp = value["timestamp"].as_str().context("Invalid timestamp")?;
 
         Ok(SnapshotConfirmed {
@@ -62,6 +62,7 @@ impl TryFrom<Value> for SnapshotConfirmed {
             confirmed_transactions,
             snapshot_number,
             utxo,
+            skipped,
             timestamp: timestamp.to_string(),
         })
     }
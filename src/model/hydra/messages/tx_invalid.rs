@@ -0,0 +1,44 @@
+use anyhow::Context;
+use serde_json::Value;
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct TxInvalid {
+    pub head_id: String,
+    pub seq: u64,
+    pub timestamp: String,
+    pub tx_id: Vec<u8>,
+    pub reason: String,
+}
+
+impl TryFrom<Value> for TxInvalid {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let head_id = value["headId"]
+            .as_str()
+            .context("Invalid head_id")?
+            .to_owned();
+        let seq = value["seq"].as_u64().context("Invalid seq")?;
+        let timestamp = value["timestamp"].as_str().context("Invalid timestamp")?;
+        let transaction = value["transaction"]
+            .as_object()
+            .context("Invalid transaction")?;
+
+        let tx_id = hex::decode(transaction["txId"].as_str().context("Invalid txId")?)?;
+
+        // The reason is free-form text from the ledger; don't fail the whole event over it
+        let reason = value["validationError"]["reason"]
+            .as_str()
+            .unwrap_or("unknown reason")
+            .to_owned();
+
+        Ok(TxInvalid {
+            head_id: head_id.to_string(),
+            seq,
+            timestamp: timestamp.to_string(),
+            tx_id,
+            reason,
+        })
+    }
+}
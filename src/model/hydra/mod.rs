@@ -1,5 +1,10 @@
+pub mod decode_failures;
+pub mod event_seq;
+pub mod head_setup;
 pub mod hydra_message;
 pub mod hydra_socket;
 pub mod messages;
 pub mod state;
 pub mod utxo;
+pub mod utxo_cache;
+pub mod value;
@@ -4,19 +4,33 @@ use tokio::sync::RwLock;
 
 use crate::model::node::Node;
 
-#[derive(Default)]
-pub struct InternalState {
-    pub nodes: Vec<Node>,
-}
+/// Each node sits behind its own lock, so work on one head never blocks
+/// readers or writers of another.
+pub type NodeHandle = Arc<RwLock<Node>>;
+
 #[derive(Clone)]
 pub struct HydraNodesState {
-    pub state: Arc<RwLock<InternalState>>,
+    pub nodes: Arc<Vec<NodeHandle>>,
 }
 
 impl HydraNodesState {
     pub fn from_nodes(nodes: Vec<Node>) -> Self {
         Self {
-            state: Arc::new(RwLock::new(InternalState { nodes })),
+            nodes: Arc::new(
+                nodes
+                    .into_iter()
+                    .map(|node| Arc::new(RwLock::new(node)))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub async fn find_by_authority(&self, authority: &str) -> Option<NodeHandle> {
+        for node in self.nodes.iter() {
+            if node.read().await.local_connection.to_authority() == authority {
+                return Some(node.clone());
+            }
         }
+        None
     }
 }
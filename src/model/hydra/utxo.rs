@@ -1,27 +1,25 @@
-use std::{collections::HashMap, fmt::Display};
+use std::fmt::Display;
 
 use anyhow::{anyhow, Context, Result};
 use derivative::Derivative;
 use pallas::{
     codec::{
         minicbor::{self, encode},
-        utils::KeepRaw,
+        utils::{Bytes, KeyValuePairs},
     },
-    crypto::hash::Hash,
+    crypto::hash::{Hash, Hasher},
     ledger::{
         addresses::Address,
-        primitives::{
-            alonzo::Value as AlonzoValue,
-            babbage::PseudoScript,
-            conway::{
-                BigInt, Constr, NativeScript, PlutusData, PolicyId, PseudoDatumOption,
-                PseudoPostAlonzoTransactionOutput,
-            },
-        },
+        primitives::conway::{BigInt, PlutusData, PseudoDatumOption, PseudoScript},
+        traverse::MultiEraOutput,
     },
     txbuilder::{Input, Output},
 };
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use super::value::Value;
+use crate::model::game_state::constr;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -30,10 +28,11 @@ pub struct Script {
     script_type: ScriptType,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ScriptType {
     PlutusV1,
     PlutusV2,
+    PlutusV3,
     NativeScript,
 }
 
@@ -46,7 +45,22 @@ pub struct UTxO {
     pub address: Address,
     datum: Datum,
     pub reference_script: Option<Script>,
-    pub value: HashMap<String, u64>,
+    pub value: Value,
+}
+
+/// A UTxO set as hydra reports it, decoded entry by entry so one output we don't
+/// understand doesn't cost us the rest of the set
+#[derive(Debug, Default)]
+pub struct UtxoSet {
+    pub utxos: Vec<UTxO>,
+    pub skipped: Vec<DecodeFailure>,
+}
+
+/// An entry of a UTxO set we couldn't decode
+#[derive(Debug, Clone, Serialize)]
+pub struct DecodeFailure {
+    pub utxo: String,
+    pub error: String,
 }
 
 #[derive(Debug, Clone)]
@@ -57,15 +71,21 @@ pub enum Datum {
 }
 
 impl UTxO {
-    pub fn try_from_value(tx_id: &str, value: &Value) -> Result<Self> {
-        let index = tx_id.split("#").collect::<Vec<&str>>()[1].parse::<u64>()?;
-        let hex_hash = tx_id.split("#").collect::<Vec<&str>>()[0];
-        let hash = hex::decode(hex_hash)?;
+    pub fn try_from_value(tx_id: &str, value: &JsonValue) -> Result<Self> {
+        let (hex_hash, index) = tx_id
+            .split_once('#')
+            .context("Invalid UTxO reference, expected <tx id>#<index>")?;
+        let index = index.parse::<u64>().context("Invalid UTxO index")?;
+        let hash = hex::decode(hex_hash).context("Invalid UTxO tx id")?;
         let address = value["address"].as_str().context("Invalid address")?;
         let address = Address::from_bech32(address)?;
         let is_inline = !value["inlineDatum"].is_null();
         let is_hash = !value["datumHash"].is_null();
-        let datum = if is_inline {
+        let datum = if let Some(raw) = value["inlineDatumRaw"].as_str() {
+            // The datum's own bytes, when hydra reports them, rather than its JSON rendering
+            let raw = hex::decode(raw).context("Invalid inlineDatumRaw")?;
+            Datum::Inline(minicbor::decode(&raw).context("Invalid inlineDatumRaw")?)
+        } else if is_inline {
             Datum::Inline(value_to_plutus_data(&value["inlineDatum"])?)
         } else if is_hash {
             Datum::Hash(hex::decode(
@@ -85,10 +105,7 @@ impl UTxO {
             None
         };
 
-        let mut value_map = HashMap::new();
-        for (key, value) in value["value"].as_object().context("Invalid value")? {
-            value_map.insert(key.to_string(), value.as_u64().context("Invalid value")?);
-        }
+        let value = Value::try_from_json(&value["value"])?;
 
         Ok(UTxO {
             hash,
@@ -96,71 +113,62 @@ impl UTxO {
             address,
             datum,
             reference_script,
-            value: value_map,
+            value,
         })
     }
 
-    pub fn try_from_pallas(
-        tx_id: &str,
-        tx_ix: u64,
-        output: &PseudoPostAlonzoTransactionOutput<
-            PseudoDatumOption<KeepRaw<'_, PlutusData>>,
-            PseudoScript<KeepRaw<'_, NativeScript>>,
-        >,
-    ) -> Result<Self> {
+    pub fn try_from_pallas(tx_id: &str, tx_ix: u64, output: &MultiEraOutput) -> Result<Self> {
         let hash = hex::decode(tx_id)?;
-        let address = Address::from_bytes(output.address.as_ref())?;
-        let datum = match &output.datum_option {
+        let address = output.address()?;
+        let datum = match output.datum() {
             Some(datum) => match datum {
                 PseudoDatumOption::Hash(hash) => Datum::Hash(hash.as_ref().to_vec()),
                 PseudoDatumOption::Data(datum) => {
-                    Datum::Inline(minicbor::decode(datum.raw_cbor())?)
+                    Datum::Inline(minicbor::decode(datum.0.raw_cbor())?)
                 }
             },
             None => Datum::None,
         };
-        let reference_script = match &output.script_ref {
+        let reference_script = match output.script_ref() {
             Some(script) => {
-                let script = &script.0;
+                // Encode just the script, not its language tag, so it matches the
+                // `cborHex` hydra reports for the same script
                 let mut cbor = Vec::new();
-                minicbor::encode(script, &mut cbor)?;
-
-                match script {
-                    PseudoScript::NativeScript(_) => Some(Script {
-                        cbor,
-                        script_type: ScriptType::NativeScript,
-                    }),
-                    PseudoScript::PlutusV1Script(_) => Some(Script {
-                        cbor,
-                        script_type: ScriptType::PlutusV1,
-                    }),
-                    PseudoScript::PlutusV2Script(_) => Some(Script {
-                        cbor,
-                        script_type: ScriptType::PlutusV2,
-                    }),
+                match &script.0 {
+                    PseudoScript::NativeScript(script) => {
+                        minicbor::encode(script, &mut cbor)?;
+                        Some(Script {
+                            cbor,
+                            script_type: ScriptType::NativeScript,
+                        })
+                    }
+                    PseudoScript::PlutusV1Script(script) => {
+                        minicbor::encode(script, &mut cbor)?;
+                        Some(Script {
+                            cbor,
+                            script_type: ScriptType::PlutusV1,
+                        })
+                    }
+                    PseudoScript::PlutusV2Script(script) => {
+                        minicbor::encode(script, &mut cbor)?;
+                        Some(Script {
+                            cbor,
+                            script_type: ScriptType::PlutusV2,
+                        })
+                    }
+                    PseudoScript::PlutusV3Script(script) => {
+                        minicbor::encode(script, &mut cbor)?;
+                        Some(Script {
+                            cbor,
+                            script_type: ScriptType::PlutusV3,
+                        })
+                    }
                 }
             }
             None => None,
         };
 
-        let mut value_map: HashMap<String, u64> = HashMap::new();
-        match &output.value {
-            AlonzoValue::Coin(coin) => {
-                value_map.insert("lovelace".to_owned(), *coin);
-            }
-            AlonzoValue::Multiasset(coin, multiasset) => {
-                value_map.insert("lovelace".to_owned(), *coin);
-                for (policy_id, assets) in multiasset.iter() {
-                    let policy_id_hex = hex::encode(policy_id.as_ref());
-                    for (asset_name, amount) in assets.iter() {
-                        value_map.insert(
-                            format!("{}#{}", policy_id_hex, hex::encode(asset_name.as_slice())),
-                            *amount,
-                        );
-                    }
-                }
-            }
-        }
+        let value = Value::from(&output.value());
 
         Ok(UTxO {
             hash,
@@ -168,11 +176,83 @@ impl UTxO {
             address,
             datum,
             reference_script,
-            value: value_map,
+            value,
         })
     }
 }
 
+impl UtxoSet {
+    /// Decodes `{ "<tx id>#<index>": <output>, .. }`; only fails if `value` isn't an object
+    pub fn try_from_json(value: &JsonValue) -> Result<Self> {
+        let mut set = UtxoSet::default();
+        for (key, value) in value.as_object().context("Invalid UTxO set object")? {
+            match UTxO::try_from_value(key, value) {
+                Ok(utxo) => set.utxos.push(utxo),
+                Err(e) => set.skipped.push(DecodeFailure {
+                    utxo: key.clone(),
+                    error: format!("{:#}", e),
+                }),
+            }
+        }
+        Ok(set)
+    }
+}
+
+impl UTxO {
+    pub fn has_reference_script(&self, hash: &Hash<28>) -> bool {
+        self.reference_script
+            .as_ref()
+            .is_some_and(|script| script.hash().is_ok_and(|h| h == *hash))
+    }
+
+    pub fn inline_datum(&self) -> Option<&PlutusData> {
+        match &self.datum {
+            Datum::Inline(datum) => Some(datum),
+            _ => None,
+        }
+    }
+}
+
+impl Script {
+    /// The script hash, as used in script addresses and by reference inputs
+    pub fn hash(&self) -> Result<Hash<28>> {
+        match self.script_type {
+            ScriptType::NativeScript => Ok(self.script_type.hash(&self.cbor)),
+            // Plutus scripts are hashed without the bytestring wrapping `cbor`
+            _ => {
+                let bytes: Bytes =
+                    minicbor::decode(&self.cbor).context("Invalid plutus script encoding")?;
+                Ok(self.script_type.hash(&bytes))
+            }
+        }
+    }
+}
+
+impl ScriptType {
+    /// The tag the ledger prefixes a script with before hashing it
+    fn language_tag(&self) -> u8 {
+        match self {
+            ScriptType::NativeScript => 0,
+            ScriptType::PlutusV1 => 1,
+            ScriptType::PlutusV2 => 2,
+            ScriptType::PlutusV3 => 3,
+        }
+    }
+
+    /// Hashes a script of this language, given its bytes as they appear on chain
+    pub fn hash(&self, script: &[u8]) -> Hash<28> {
+        let mut hasher = Hasher::<224>::new();
+        hasher.input(&[self.language_tag()]);
+        hasher.input(script);
+        hasher.finalize()
+    }
+}
+
+/// Finds a UTxO carrying the reference script with the given hash
+pub fn find_reference_script<'a>(utxos: &'a [UTxO], hash: &Hash<28>) -> Option<&'a UTxO> {
+    utxos.iter().find(|utxo| utxo.has_reference_script(hash))
+}
+
 impl Display for UTxO {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}#{}", hex::encode(&self.hash), self.index)
@@ -186,7 +266,8 @@ impl From<UTxO> for Input {
     }
 }
 
-fn value_to_plutus_data(value: &Value) -> Result<PlutusData> {
+/// Decodes the cardano-api JSON rendering of plutus data (the "detailed schema")
+fn value_to_plutus_data(value: &JsonValue) -> Result<PlutusData> {
     let value = value
         .as_object()
         .context("Invalid PlutusData json encoding")?;
@@ -196,18 +277,14 @@ fn value_to_plutus_data(value: &Value) -> Result<PlutusData> {
             .context("key constructor not found")?
             .as_u64()
             .context("Invalid constructor")?;
-        let fields: Vec<PlutusData> = value["fields"]
+        let fields = value["fields"]
             .as_array()
             .context("Invalid fields")?
             .iter()
-            .filter_map(|v| value_to_plutus_data(v).ok())
-            .collect();
+            .map(value_to_plutus_data)
+            .collect::<Result<Vec<_>>>()?;
 
-        Ok(PlutusData::Constr(Constr {
-            tag: 121,
-            any_constructor: Some(constructor),
-            fields,
-        }))
+        Ok(constr(constructor, fields))
     } else if value.contains_key("int") {
         let int = value
             .get("int")
@@ -225,7 +302,26 @@ fn value_to_plutus_data(value: &Value) -> Result<PlutusData> {
         let bytes = hex::decode(bytes)?;
         Ok(PlutusData::BoundedBytes(bytes.into()))
     } else if value.contains_key("list") {
-        Err(anyhow!("plutus list decoding not yet implemented"))
+        let list = value["list"]
+            .as_array()
+            .context("Invalid list")?
+            .iter()
+            .map(value_to_plutus_data)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PlutusData::Array(list))
+    } else if value.contains_key("map") {
+        let map = value["map"]
+            .as_array()
+            .context("Invalid map")?
+            .iter()
+            .map(|entry| {
+                Ok((
+                    value_to_plutus_data(&entry["k"])?,
+                    value_to_plutus_data(&entry["v"])?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PlutusData::Map(KeyValuePairs::Def(map)))
     } else {
         Err(anyhow!("Invalid PlutusData json encoding"))
     }
@@ -234,20 +330,7 @@ impl TryInto<Output> for UTxO {
     type Error = anyhow::Error;
 
     fn try_into(self) -> Result<Output, Self::Error> {
-        let lovelace: u64 = *self.value.get("lovelace").unwrap_or(&u64::default());
-
-        let mut output = Output::new(self.address, lovelace);
-        for (asset_id, count) in self.value {
-            if asset_id == "lovelace" {
-                continue;
-            }
-
-            let asset_id = hex::decode(asset_id)?;
-            let policy_id: [u8; 28] = asset_id[0..28].try_into()?;
-            let policy_id: PolicyId = policy_id.into();
-            let asset_name = asset_id[28..].to_vec();
-            output = output.add_asset(policy_id, asset_name, count)?;
-        }
+        let mut output = self.value.to_output(self.address)?;
 
         match self.datum {
             Datum::Hash(datum) => {
@@ -266,10 +349,10 @@ impl TryInto<Output> for UTxO {
     }
 }
 
-impl TryFrom<&Value> for Script {
+impl TryFrom<&JsonValue> for Script {
     type Error = anyhow::Error;
 
-    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+    fn try_from(value: &JsonValue) -> Result<Self, Self::Error> {
         let value = value
             .as_object()
             .context("invalid referenceScript object")?["script"]
@@ -293,8 +376,167 @@ impl TryFrom<&str> for ScriptType {
         match value {
             "PlutusScriptV1" => Ok(ScriptType::PlutusV1),
             "PlutusScriptV2" => Ok(ScriptType::PlutusV2),
-            "NativeScript" => Ok(ScriptType::NativeScript),
-            _ => Err(anyhow!("Invalid ScriptType")),
+            "PlutusScriptV3" => Ok(ScriptType::PlutusV3),
+            "NativeScript" | "SimpleScript" => Ok(ScriptType::NativeScript),
+            _ => Err(anyhow!("Invalid ScriptType: {}", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{SCRIPT_ADDRESS, SCRIPT_CBOR};
+
+    fn script_address_hash() -> Hash<28> {
+        match Address::from_bech32(SCRIPT_ADDRESS).unwrap() {
+            Address::Shelley(address) => *address.payment().as_hash(),
+            _ => panic!("script address should be a shelley address"),
+        }
+    }
+
+    /// A UTxO at the script address carrying `script` as its reference script
+    fn utxo_with_script(cbor_hex: &str, script_type: &str) -> UTxO {
+        UTxO::try_from_value(
+            "0000000000000000000000000000000000000000000000000000000000000000#0",
+            &json!({
+                "address": SCRIPT_ADDRESS,
+                "value": { "lovelace": 0 },
+                "referenceScript": {
+                    "script": { "cborHex": cbor_hex, "type": script_type },
+                },
+            }),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn script_hash_matches_script_address() {
+        let hash = ScriptType::PlutusV2.hash(&hex::decode(SCRIPT_CBOR).unwrap());
+        assert_eq!(hash, script_address_hash());
+    }
+
+    #[test]
+    fn reference_script_is_hashed_without_its_cbor_wrapping() {
+        // Hydra reports the script wrapped in another CBOR bytestring (913 bytes long)
+        let utxo = utxo_with_script(&format!("590391{}", SCRIPT_CBOR), "PlutusScriptV2");
+
+        assert!(utxo.has_reference_script(&script_address_hash()));
+    }
+
+    #[test]
+    fn plutus_v3_reference_script() {
+        let utxo = utxo_with_script("4f4e4d01000033222220051200120011", "PlutusScriptV3");
+        let script = utxo.reference_script.as_ref().unwrap();
+
+        assert_eq!(script.script_type, ScriptType::PlutusV3);
+        assert_eq!(
+            hex::encode(script.hash().unwrap()),
+            "13bb6c9c8030b09fc4e85ccdf07aa7bf640d3259e9d4f661c892bfa3"
+        );
+    }
+
+    #[test]
+    fn plutus_data_from_json() {
+        let data = value_to_plutus_data(&json!({
+            "constructor": 1,
+            "fields": [
+                { "int": -3 },
+                { "bytes": "adad" },
+                { "list": [{ "int": 1 }, { "constructor": 7, "fields": [] }] },
+                { "map": [{ "k": { "int": 1 }, "v": { "bytes": "" } }] },
+            ],
+        }))
+        .unwrap();
+
+        let PlutusData::Constr(constr) = data else {
+            panic!("expected a constructor");
+        };
+        assert_eq!(constr.tag, 122);
+        assert_eq!(constr.fields.len(), 4);
+        match &constr.fields[2] {
+            PlutusData::Array(list) => {
+                assert_eq!(list.len(), 2);
+                assert!(matches!(&list[1], PlutusData::Constr(c) if c.tag == 1280));
+            }
+            other => panic!("expected a list, got {:?}", other),
+        }
+        assert!(matches!(&constr.fields[3], PlutusData::Map(_)));
+    }
+
+    #[test]
+    fn bad_field_fails_the_whole_datum() {
+        let result = value_to_plutus_data(&json!({
+            "constructor": 0,
+            "fields": [{ "int": 1 }, { "float": 1.5 }],
+        }));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn raw_inline_datum_is_preferred() {
+        let mut raw = Vec::new();
+        encode(PlutusData::BigInt(BigInt::Int(42i64.into())), &mut raw).unwrap();
+
+        let utxo = UTxO::try_from_value(
+            "0000000000000000000000000000000000000000000000000000000000000000#0",
+            &json!({
+                "address": SCRIPT_ADDRESS,
+                "value": { "lovelace": 0 },
+                // Disagrees with the raw bytes, to tell which one was used
+                "inlineDatum": { "int": 7 },
+                "inlineDatumRaw": hex::encode(raw),
+            }),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            utxo.inline_datum(),
+            Some(PlutusData::BigInt(BigInt::Int(int))) if i128::from(int.0) == 42
+        ));
+    }
+
+    #[test]
+    fn malformed_references_are_an_error() {
+        let output = json!({ "address": SCRIPT_ADDRESS, "value": { "lovelace": 0 } });
+        let tx_id = "00".repeat(32);
+
+        for reference in [
+            tx_id.clone(),
+            format!("{}#", tx_id),
+            format!("{}#one", tx_id),
+            format!("{}#-1", tx_id),
+            "zz#0".to_string(),
+            "#".to_string(),
+            String::new(),
+        ] {
+            assert!(
+                UTxO::try_from_value(&reference, &output).is_err(),
+                "{:?} should be rejected",
+                reference
+            );
         }
+        assert_eq!(
+            UTxO::try_from_value(&format!("{}#3", tx_id), &output)
+                .unwrap()
+                .index,
+            3
+        );
+    }
+
+    #[test]
+    fn malformed_reference_doesnt_cost_the_set() {
+        let set = UtxoSet::try_from_json(&json!({
+            "no-index": { "address": SCRIPT_ADDRESS, "value": { "lovelace": 0 } },
+            format!("{}#0", "00".repeat(32)): { "address": SCRIPT_ADDRESS, "value": { "lovelace": 0 } },
+        }))
+        .unwrap();
+
+        assert_eq!(set.utxos.len(), 1);
+        assert_eq!(set.skipped.len(), 1);
+        assert_eq!(set.skipped[0].utxo, "no-index");
     }
 }
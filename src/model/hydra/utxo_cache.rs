@@ -0,0 +1,286 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use pallas::ledger::traverse::MultiEraTx;
+use tracing::warn;
+
+use super::utxo::UTxO;
+
+type OutputRef = (Vec<u8>, u64);
+
+/// The node's view of the head's UTxO set, kept up to date from hydra events.
+///
+/// Shared between the node and its clients, so placement can read it without the node lock.
+#[derive(Clone, Default)]
+pub struct UtxoCache(Arc<RwLock<CacheState>>);
+
+#[derive(Default)]
+struct CacheState {
+    /// `None` until the node has told us about its UTxO set, or after we lost track of it
+    confirmed: Option<HashMap<OutputRef, UTxO>>,
+    /// Transactions seen in `TxValid` but not yet in a snapshot, in the order they were seen
+    unconfirmed: Vec<CachedTransaction>,
+}
+
+struct CachedTransaction {
+    tx_id: Vec<u8>,
+    spent: Vec<OutputRef>,
+    produced: Vec<UTxO>,
+}
+
+impl UtxoCache {
+    /// Replaces the cached set with a full UTxO set reported by the node
+    pub fn replace(&self, utxos: Vec<UTxO>) {
+        let mut state = self.0.write().expect("utxo cache lock poisoned");
+        state.confirmed = Some(index(utxos));
+        state.unconfirmed.clear();
+    }
+
+    /// Replaces the cached set with a snapshot's, keeping transactions that were seen
+    /// since but aren't part of it yet.
+    ///
+    /// A transaction is only kept while everything it spends is still there, either in the
+    /// snapshot or produced by another transaction kept before it; otherwise it can no
+    /// longer land (ie. its inputs were spent by something else, or it was confirmed
+    /// without us hearing about it).
+    pub fn snapshot(&self, utxos: Vec<UTxO>, confirmed_transactions: &[Vec<u8>]) {
+        let mut state = self.0.write().expect("utxo cache lock poisoned");
+        let confirmed = index(utxos);
+        let mut available: HashSet<OutputRef> = confirmed.keys().cloned().collect();
+        state.unconfirmed.retain(|tx| {
+            let keep = !confirmed_transactions.contains(&tx.tx_id)
+                && tx.spent.iter().all(|spent| available.contains(spent));
+            if keep {
+                for spent in tx.spent.iter() {
+                    available.remove(spent);
+                }
+                available.extend(
+                    tx.produced
+                        .iter()
+                        .map(|utxo| (utxo.hash.clone(), utxo.index)),
+                );
+            }
+            keep
+        });
+        state.confirmed = Some(confirmed);
+    }
+
+    /// Applies a transaction ahead of the snapshot that confirms it, either one the head
+    /// accepted or one we just submitted; applying the same transaction again does nothing
+    pub fn apply(&self, tx_id: Vec<u8>, spent: Vec<OutputRef>, produced: Vec<UTxO>) {
+        let mut state = self.0.write().expect("utxo cache lock poisoned");
+        if state.confirmed.is_some() && !state.unconfirmed.iter().any(|tx| tx.tx_id == tx_id) {
+            state.unconfirmed.push(CachedTransaction {
+                tx_id,
+                spent,
+                produced,
+            });
+        }
+    }
+
+    /// Applies a decoded transaction; see `apply`
+    pub fn apply_transaction(&self, tx_id: &[u8], tx: &MultiEraTx) {
+        let spent = tx
+            .inputs()
+            .iter()
+            .map(|input| (input.hash().to_vec(), input.index()))
+            .collect();
+        let tx_id_hex = hex::encode(tx_id);
+        let produced = tx
+            .outputs()
+            .iter()
+            .enumerate()
+            .map(|(index, output)| UTxO::try_from_pallas(&tx_id_hex, index as u64, output))
+            .collect::<Result<Vec<UTxO>>>();
+
+        match produced {
+            Ok(produced) => self.apply(tx_id.to_vec(), spent, produced),
+            Err(e) => {
+                // Better to fall back to fetching the UTxO set than to hand out a wrong one
+                warn!(
+                    "failed to apply transaction {} to the utxo cache: {:?}",
+                    tx_id_hex, e
+                );
+                self.invalidate();
+            }
+        }
+    }
+
+    /// Takes back a transaction we applied that the head didn't accept, along with those
+    /// applied after it that spend its outputs, which can't land either
+    pub fn discard(&self, tx_id: &[u8]) {
+        let mut state = self.0.write().expect("utxo cache lock poisoned");
+        let mut discarded = vec![tx_id.to_vec()];
+        state.unconfirmed.retain(|tx| {
+            let discard = discarded.contains(&tx.tx_id)
+                || tx.spent.iter().any(|(hash, _)| discarded.contains(hash));
+            if discard {
+                discarded.push(tx.tx_id.clone());
+            }
+            !discard
+        });
+    }
+
+    /// Forgets the cached set, ie. when a transaction couldn't be applied to it; it is
+    /// rebuilt from the next snapshot
+    pub fn invalidate(&self) {
+        let mut state = self.0.write().expect("utxo cache lock poisoned");
+        state.confirmed = None;
+        state.unconfirmed.clear();
+    }
+
+    /// The current UTxO set, or `None` if we don't know it
+    pub fn utxos(&self) -> Option<Vec<UTxO>> {
+        let state = self.0.read().expect("utxo cache lock poisoned");
+        let mut utxos = state.confirmed.clone()?;
+        for tx in state.unconfirmed.iter() {
+            for spent in tx.spent.iter() {
+                utxos.remove(spent);
+            }
+            for utxo in tx.produced.iter() {
+                utxos.insert((utxo.hash.clone(), utxo.index), utxo.clone());
+            }
+        }
+        Some(utxos.into_values().collect())
+    }
+}
+
+fn index(utxos: Vec<UTxO>) -> HashMap<OutputRef, UTxO> {
+    utxos
+        .into_iter()
+        .map(|utxo| ((utxo.hash.clone(), utxo.index), utxo))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::SCRIPT_ADDRESS;
+
+    fn tx_id(id: u8) -> Vec<u8> {
+        vec![id; 32]
+    }
+
+    fn utxo(id: u8, index: u64) -> UTxO {
+        UTxO::try_from_value(
+            &format!("{}#{}", hex::encode(tx_id(id)), index),
+            &json!({ "address": SCRIPT_ADDRESS, "value": { "lovelace": 1_000_000 } }),
+        )
+        .unwrap()
+    }
+
+    fn output_ref(id: u8, index: u64) -> OutputRef {
+        (tx_id(id), index)
+    }
+
+    /// The cached set as sorted `(tx id byte, index)` pairs
+    fn refs(cache: &UtxoCache) -> Option<Vec<(u8, u64)>> {
+        let mut refs: Vec<(u8, u64)> = cache
+            .utxos()?
+            .into_iter()
+            .map(|utxo| (utxo.hash[0], utxo.index))
+            .collect();
+        refs.sort();
+        Some(refs)
+    }
+
+    /// A cache holding the outputs of transaction 1, with transaction 2 spending its first
+    /// output and transaction 3 spending transaction 2's in turn
+    fn chained() -> UtxoCache {
+        let cache = UtxoCache::default();
+        cache.replace(vec![utxo(1, 0), utxo(1, 1)]);
+        cache.apply(tx_id(2), vec![output_ref(1, 0)], vec![utxo(2, 0)]);
+        cache.apply(tx_id(3), vec![output_ref(2, 0)], vec![utxo(3, 0)]);
+        cache
+    }
+
+    #[test]
+    fn unknown_until_replaced() {
+        let cache = UtxoCache::default();
+        cache.apply(tx_id(2), vec![], vec![utxo(2, 0)]);
+        assert_eq!(refs(&cache), None);
+
+        cache.replace(vec![utxo(1, 0)]);
+        assert_eq!(refs(&cache), Some(vec![(1, 0)]));
+    }
+
+    #[test]
+    fn applied_transactions_spend_and_produce() {
+        let cache = chained();
+        assert_eq!(refs(&cache), Some(vec![(1, 1), (3, 0)]));
+
+        // Hearing about a transaction twice changes nothing
+        cache.apply(tx_id(2), vec![output_ref(1, 0)], vec![utxo(2, 0)]);
+        assert_eq!(refs(&cache), Some(vec![(1, 1), (3, 0)]));
+    }
+
+    #[test]
+    fn snapshot_keeps_transactions_it_doesnt_include_yet() {
+        let cache = chained();
+
+        // Confirms transaction 2 only
+        cache.snapshot(vec![utxo(1, 1), utxo(2, 0)], &[tx_id(2)]);
+
+        assert_eq!(refs(&cache), Some(vec![(1, 1), (3, 0)]));
+    }
+
+    #[test]
+    fn snapshot_drops_transactions_that_can_no_longer_land() {
+        let cache = chained();
+
+        // Something else spent transaction 1's first output, so neither 2 nor 3 can land
+        cache.snapshot(vec![utxo(1, 1), utxo(4, 0)], &[tx_id(4)]);
+
+        assert_eq!(refs(&cache), Some(vec![(1, 1), (4, 0)]));
+    }
+
+    #[test]
+    fn snapshot_drops_transactions_it_confirms() {
+        let cache = chained();
+
+        cache.snapshot(vec![utxo(1, 1), utxo(3, 0)], &[tx_id(2), tx_id(3)]);
+        assert_eq!(refs(&cache), Some(vec![(1, 1), (3, 0)]));
+
+        // Nothing left to re-apply on top of the next one
+        cache.snapshot(vec![utxo(1, 1)], &[]);
+        assert_eq!(refs(&cache), Some(vec![(1, 1)]));
+    }
+
+    #[test]
+    fn discard_takes_back_transactions_spending_its_outputs() {
+        let cache = chained();
+        cache.apply(tx_id(5), vec![output_ref(1, 1)], vec![utxo(5, 0)]);
+
+        cache.discard(&tx_id(2));
+
+        // 3 spent 2's output, so it goes too; 5 didn't depend on either
+        assert_eq!(refs(&cache), Some(vec![(1, 0), (5, 0)]));
+    }
+
+    #[test]
+    fn discarding_the_last_transaction_leaves_the_rest() {
+        let cache = chained();
+
+        cache.discard(&tx_id(3));
+
+        assert_eq!(refs(&cache), Some(vec![(1, 1), (2, 0)]));
+    }
+
+    #[test]
+    fn invalidate_forgets_everything_until_replaced() {
+        let cache = chained();
+
+        cache.invalidate();
+        assert_eq!(refs(&cache), None);
+        cache.apply(tx_id(6), vec![], vec![utxo(6, 0)]);
+        assert_eq!(refs(&cache), None);
+
+        cache.replace(vec![utxo(1, 0)]);
+        assert_eq!(refs(&cache), Some(vec![(1, 0)]));
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    ops::{Add, AddAssign},
+};
+
+use anyhow::{anyhow, Context, Result};
+use pallas::{
+    crypto::hash::Hash,
+    ledger::{addresses::Address, traverse::MultiEraValue},
+    txbuilder::Output,
+};
+
+/// A native asset, identified by its minting policy and asset name
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AssetId {
+    pub policy: Hash<28>,
+    pub name: Vec<u8>,
+}
+
+/// An amount of lovelace plus any number of native assets
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Value {
+    pub lovelace: u64,
+    /// Never holds zero quantities
+    assets: BTreeMap<AssetId, u64>,
+}
+
+impl Display for AssetId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}",
+            hex::encode(self.policy),
+            hex::encode(&self.name)
+        )
+    }
+}
+
+impl Value {
+    pub fn lovelace(lovelace: u64) -> Self {
+        Value {
+            lovelace,
+            assets: BTreeMap::new(),
+        }
+    }
+
+    fn add_asset(&mut self, asset: AssetId, quantity: u64) {
+        if quantity > 0 {
+            *self.assets.entry(asset).or_default() += quantity;
+        }
+    }
+
+    /// Parses a value as hydra encodes it in JSON, ie.
+    /// `{ "lovelace": 1000000, "<policy id>": { "<asset name>": 1 } }`
+    pub fn try_from_json(value: &serde_json::Value) -> Result<Self> {
+        let mut result = Value::default();
+        for (key, quantity) in value.as_object().context("Invalid value")? {
+            if key == "lovelace" {
+                result.lovelace = quantity.as_u64().context("Invalid lovelace")?;
+                continue;
+            }
+
+            let policy: [u8; 28] = hex::decode(key)
+                .context("Invalid policy id")?
+                .try_into()
+                .map_err(|_| anyhow!("Invalid policy id length"))?;
+            for (name, quantity) in quantity.as_object().context("Invalid assets")? {
+                result.add_asset(
+                    AssetId {
+                        policy: policy.into(),
+                        name: hex::decode(name).context("Invalid asset name")?,
+                    },
+                    quantity.as_u64().context("Invalid asset quantity")?,
+                );
+            }
+        }
+        Ok(result)
+    }
+
+    /// An output paying this value to `address`
+    pub fn to_output(&self, address: Address) -> Result<Output> {
+        let mut output = Output::new(address, self.lovelace);
+        for (asset, quantity) in self.assets.iter() {
+            output = output.add_asset(asset.policy, asset.name.clone(), *quantity)?;
+        }
+        Ok(output)
+    }
+}
+
+impl From<&MultiEraValue<'_>> for Value {
+    fn from(value: &MultiEraValue) -> Self {
+        let mut result = Value::lovelace(value.coin());
+        for policy_assets in value.assets() {
+            for asset in policy_assets.assets() {
+                result.add_asset(
+                    AssetId {
+                        policy: *policy_assets.policy(),
+                        name: asset.name().to_vec(),
+                    },
+                    asset.output_coin().unwrap_or_default(),
+                );
+            }
+        }
+        result
+    }
+}
+
+impl Add for Value {
+    type Output = Value;
+
+    fn add(mut self, rhs: Value) -> Self::Output {
+        self += rhs;
+        self
+    }
+}
+
+impl AddAssign for Value {
+    fn add_assign(&mut self, rhs: Value) {
+        self.lovelace += rhs.lovelace;
+        for (asset, quantity) in rhs.assets {
+            self.add_asset(asset, quantity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const POLICY: [u8; 28] = [0xa0; 28];
+
+    fn asset(name: &str) -> AssetId {
+        AssetId {
+            policy: Hash::new(POLICY),
+            name: name.as_bytes().to_vec(),
+        }
+    }
+
+    fn value(lovelace: u64, assets: &[(&str, u64)]) -> Value {
+        let mut value = Value::lovelace(lovelace);
+        for (name, quantity) in assets {
+            value.add_asset(asset(name), *quantity);
+        }
+        value
+    }
+
+    // Asset names are hex encoded: "trophy" and "ticket"
+    #[test]
+    fn parses_lovelace_and_assets() {
+        let parsed = Value::try_from_json(&json!({
+            "lovelace": 1500000,
+            "a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0": { "74726f706879": 2, "7469636b6574": 1 }
+        }))
+        .unwrap();
+
+        assert_eq!(parsed, value(1500000, &[("trophy", 2), ("ticket", 1)]));
+    }
+
+    #[test]
+    fn parsing_drops_zero_quantities() {
+        let parsed = Value::try_from_json(&json!({
+            "lovelace": 0,
+            "a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0a0": { "74726f706879": 0 }
+        }))
+        .unwrap();
+
+        assert_eq!(parsed, Value::default());
+    }
+
+    #[test]
+    fn parsing_rejects_a_short_policy_id() {
+        assert!(Value::try_from_json(&json!({ "abcd": { "": 1 } })).is_err());
+    }
+
+    #[test]
+    fn adds_lovelace_and_assets() {
+        let sum = value(1, &[("trophy", 2)]) + value(3, &[("trophy", 1), ("ticket", 4)]);
+
+        assert_eq!(sum, value(4, &[("trophy", 3), ("ticket", 4)]));
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+use super::error::ControlPlaneError;
+
+/// How long a key is remembered; a client retrying later than this creates a new resource
+const RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many keys are remembered at most; past that, the oldest are forgotten first
+const CAPACITY: usize = 10_000;
+
+/// Remembers the outcome of requests by their `Idempotency-Key`, so a replayed request
+/// gets the original response instead of doing the work twice.
+///
+/// Keys are scoped to the requester (ie. the client's IP), as responses can carry secrets
+/// like a game's end token: someone else sending the same key gets their own response.
+#[derive(Clone)]
+pub struct IdempotencyStore<R, T> {
+    entries: Arc<Mutex<HashMap<(String, String), Entry<R, T>>>>,
+    capacity: usize,
+}
+
+struct Entry<R, T> {
+    request: R,
+    response: Arc<OnceCell<T>>,
+    sent: Recorder<T>,
+    created_at: Instant,
+}
+
+/// Where a request records its response as soon as it can't be taken back, ie. once its
+/// transaction is sent, even if it goes on to fail waiting for the head
+pub struct Recorder<T>(Arc<Mutex<Option<T>>>);
+
+impl<T> Default for Recorder<T> {
+    fn default() -> Self {
+        Recorder(Arc::new(Mutex::new(None)))
+    }
+}
+
+impl<T> Clone for Recorder<T> {
+    fn clone(&self) -> Self {
+        Recorder(self.0.clone())
+    }
+}
+
+impl<T: Clone> Recorder<T> {
+    pub fn record(&self, response: T) {
+        *self.0.lock().expect("idempotency lock poisoned") = Some(response);
+    }
+
+    fn recorded(&self) -> Option<T> {
+        self.0.lock().expect("idempotency lock poisoned").clone()
+    }
+
+    fn clear(&self) {
+        self.0.lock().expect("idempotency lock poisoned").take();
+    }
+}
+
+impl<R, T> Default for IdempotencyStore<R, T> {
+    fn default() -> Self {
+        IdempotencyStore {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            capacity: CAPACITY,
+        }
+    }
+}
+
+impl<R, T> IdempotencyStore<R, T>
+where
+    R: Clone + PartialEq,
+    T: Clone,
+{
+    /// Runs `handle` for the first request with `key` from `requester`, and returns its
+    /// response to every request from them with the same key after it. Concurrent requests
+    /// wait for the first one.
+    ///
+    /// Failures aren't remembered, so a retry after an error (ie. no capacity) tries again;
+    /// unless `handle` had recorded its response by then, in which case the retry gets that
+    /// (ie. after timing out waiting for a game transaction that was sent). Only a
+    /// transaction the head rejected is known not to have happened, and is tried again.
+    pub async fn run<F, Fut>(
+        &self,
+        requester: &str,
+        key: &str,
+        request: R,
+        handle: F,
+    ) -> Result<T, ControlPlaneError>
+    where
+        F: FnOnce(R, Recorder<T>) -> Fut,
+        Fut: Future<Output = Result<T, ControlPlaneError>>,
+    {
+        let scoped = (requester.to_string(), key.to_string());
+        let (response, sent) = {
+            let mut entries = self.entries.lock().expect("idempotency lock poisoned");
+            entries.retain(|_, entry| entry.created_at.elapsed() < RETENTION);
+            if !entries.contains_key(&scoped) && entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.created_at)
+                    .map(|(scoped, _)| scoped.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            let entry = entries.entry(scoped.clone()).or_insert_with(|| Entry {
+                request: request.clone(),
+                response: Arc::new(OnceCell::new()),
+                sent: Recorder::default(),
+                created_at: Instant::now(),
+            });
+            if entry.request != request {
+                return Err(ControlPlaneError::IdempotencyConflict(key.to_string()));
+            }
+            (entry.response.clone(), entry.sent.clone())
+        };
+
+        let result = response
+            .get_or_try_init(|| async {
+                if let Some(response) = sent.recorded() {
+                    return Ok(response);
+                }
+                let result = handle(request, sent.clone()).await;
+                if let Err(ControlPlaneError::TxRejected(_)) = result {
+                    sent.clear();
+                }
+                result
+            })
+            .await
+            .cloned();
+
+        if result.is_err() && sent.recorded().is_none() {
+            // Nothing to replay, so don't hold on to it; unless another request with the
+            // key is still waiting on it
+            let mut entries = self.entries.lock().expect("idempotency lock poisoned");
+            let unused = entries.get(&scoped).is_some_and(|entry| {
+                Arc::ptr_eq(&entry.response, &response) && Arc::strong_count(&response) <= 2
+            });
+            if unused {
+                entries.remove(&scoped);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_returns_the_original_response() {
+        let store = IdempotencyStore::<u8, u8>::default();
+        assert_eq!(
+            store
+                .run("alice", "key", 1, |_, _| async { Ok(7) })
+                .await
+                .unwrap(),
+            7
+        );
+        assert_eq!(
+            store
+                .run("alice", "key", 1, |_, _| async { Ok(8) })
+                .await
+                .unwrap(),
+            7
+        );
+        assert!(matches!(
+            store.run("alice", "key", 2, |_, _| async { Ok(8) }).await,
+            Err(ControlPlaneError::IdempotencyConflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn retry_after_a_failure_tries_again() {
+        let store = IdempotencyStore::<u8, u8>::default();
+        let failed = store
+            .run("alice", "key", 1, |_, _| async {
+                Err(ControlPlaneError::NoCapacity)
+            })
+            .await;
+        assert!(matches!(failed, Err(ControlPlaneError::NoCapacity)));
+        assert_eq!(
+            store
+                .run("alice", "key", 1, |_, _| async { Ok(8) })
+                .await
+                .unwrap(),
+            8
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_a_timeout_returns_the_sent_response() {
+        let store = IdempotencyStore::<u8, u8>::default();
+        let timed_out = store
+            .run("alice", "key", 1, |_, sent| async move {
+                sent.record(7);
+                Err(ControlPlaneError::SubmissionTimeout("slow".to_string()))
+            })
+            .await;
+        assert!(matches!(
+            timed_out,
+            Err(ControlPlaneError::SubmissionTimeout(_))
+        ));
+        assert_eq!(
+            store
+                .run("alice", "key", 1, |_, _| async { Ok(8) })
+                .await
+                .unwrap(),
+            7
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_a_rejection_tries_again() {
+        let store = IdempotencyStore::<u8, u8>::default();
+        let rejected = store
+            .run("alice", "key", 1, |_, sent| async move {
+                sent.record(7);
+                Err(ControlPlaneError::TxRejected("bad".to_string()))
+            })
+            .await;
+        assert!(matches!(rejected, Err(ControlPlaneError::TxRejected(_))));
+        assert_eq!(
+            store
+                .run("alice", "key", 1, |_, _| async { Ok(8) })
+                .await
+                .unwrap(),
+            8
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_are_scoped_to_the_requester() {
+        let store = IdempotencyStore::<u8, u8>::default();
+        assert_eq!(
+            store
+                .run("alice", "key", 1, |_, _| async { Ok(7) })
+                .await
+                .unwrap(),
+            7
+        );
+
+        assert_eq!(
+            store
+                .run("mallory", "key", 1, |_, _| async { Ok(8) })
+                .await
+                .unwrap(),
+            8
+        );
+        assert_eq!(
+            store
+                .run("alice", "key", 1, |_, _| async { Ok(9) })
+                .await
+                .unwrap(),
+            7
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_are_forgotten() {
+        let store = IdempotencyStore::<u8, u8>::default();
+        for key in ["a", "b", "c"] {
+            let _ = store
+                .run("alice", key, 1, |_, _| async {
+                    Err(ControlPlaneError::NoCapacity)
+                })
+                .await;
+        }
+
+        assert!(store.entries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sent_failures_are_kept() {
+        let store = IdempotencyStore::<u8, u8>::default();
+        let _ = store
+            .run("alice", "key", 1, |_, sent| async move {
+                sent.record(7);
+                Err(ControlPlaneError::SubmissionTimeout("slow".to_string()))
+            })
+            .await;
+
+        assert_eq!(store.entries.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oldest_key_is_forgotten_past_capacity() {
+        let store = IdempotencyStore::<u8, u8> {
+            entries: Default::default(),
+            capacity: 2,
+        };
+        for (key, response) in [("a", 1), ("b", 2), ("c", 3)] {
+            store
+                .run("alice", key, 0, |_, _| async move { Ok(response) })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(store.entries.lock().unwrap().len(), 2);
+        // "a" made room for "c", so it runs again
+        assert_eq!(
+            store
+                .run("alice", "c", 0, |_, _| async { Ok(9) })
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            store
+                .run("alice", "a", 0, |_, _| async { Ok(9) })
+                .await
+                .unwrap(),
+            9
+        );
+    }
+}
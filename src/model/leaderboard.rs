@@ -0,0 +1,702 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use rocket::{form::ValueField, request::FromParam, FromFormField};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::LeaderboardConfig;
+
+/// Kills, items and secrets above this are from buggy clients, not skilled players
+const MAX_PLAUSIBLE_SCORE: u64 = 10000;
+
+/// What a leaderboard ranks players by
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    FromFormField,
+    Serialize,
+    Deserialize,
+    ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Most kills in a single game
+    #[field(value = "kills")]
+    Kills,
+    /// Most items picked up in a single game
+    #[field(value = "items")]
+    Items,
+    /// Most secrets found in a single game
+    #[field(value = "secrets")]
+    Secrets,
+    /// Quickest level completion, in game tics
+    #[field(value = "fastest_level")]
+    FastestLevel,
+    /// Most bytes of game transactions
+    #[field(value = "bytes")]
+    Bytes,
+    /// Most games finished
+    #[field(value = "games_played")]
+    GamesPlayed,
+}
+
+impl Metric {
+    pub fn all() -> Vec<Metric> {
+        vec![
+            Metric::Kills,
+            Metric::Items,
+            Metric::Secrets,
+            Metric::FastestLevel,
+            Metric::Bytes,
+            Metric::GamesPlayed,
+        ]
+    }
+
+    /// Combines a player's score with a new one, whether from another game or another node
+    fn combine(self, current: u64, score: u64) -> u64 {
+        match self {
+            Metric::Kills | Metric::Items | Metric::Secrets => current.max(score),
+            Metric::FastestLevel => current.min(score),
+            Metric::Bytes | Metric::GamesPlayed => current + score,
+        }
+    }
+
+    /// Orders scores best first
+    fn rank(self, a: u64, b: u64) -> Ordering {
+        match self {
+            Metric::FastestLevel => a.cmp(&b),
+            _ => b.cmp(&a),
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for Metric {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        <Metric as rocket::form::FromFormField>::from_value(ValueField::from_value(param))
+            .map_err(|_| param)
+    }
+}
+
+/// The period a leaderboard covers
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    FromFormField,
+    Serialize,
+    Deserialize,
+    ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Window {
+    #[field(value = "all_time")]
+    AllTime,
+    /// Since midnight UTC
+    #[field(value = "daily")]
+    Daily,
+    /// Between the configured event's start and end
+    #[field(value = "event")]
+    Event,
+}
+
+/// An event with its own leaderboards, in seconds since the epoch
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct EventWindow {
+    pub starts_at: u64,
+    pub ends_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LeaderboardEntry {
+    /// The player's payment key hash, hex encoded
+    pub player: String,
+    pub score: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Leaderboard {
+    /// When the window this board covers started, in seconds since the epoch
+    pub since: u64,
+    /// The top players, best first
+    pub entries: Vec<LeaderboardEntry>,
+    /// Every player's score in the window, by payment key hash; the board is ranked from
+    /// these, so a score adding up over many games counts in full even while it's outside
+    /// the top. Too many to show with the board, so they're saved separately (see
+    /// `Leaderboards::scores`).
+    #[serde(skip)]
+    pub scores: Arc<BTreeMap<String, u64>>,
+}
+
+impl Leaderboard {
+    /// Records a player's score; with a `capacity`, the worst scores short of the board are
+    /// dropped to stay within it
+    fn record(
+        &mut self,
+        metric: Metric,
+        player: &str,
+        score: u64,
+        size: usize,
+        capacity: Option<usize>,
+    ) {
+        let scores = Arc::make_mut(&mut self.scores);
+        let score = scores
+            .get(player)
+            .map_or(score, |current| metric.combine(*current, score));
+        scores.insert(player.to_string(), score);
+        // Scores only ever get better, so nobody but this player can move into the top
+        match self.entries.iter_mut().find(|entry| entry.player == player) {
+            Some(entry) => entry.score = score,
+            None => self.entries.push(LeaderboardEntry {
+                player: player.to_string(),
+                score,
+            }),
+        }
+        self.sort(metric, size);
+        if let Some(capacity) = capacity {
+            self.bound(metric, capacity);
+        }
+    }
+
+    /// Drops the worst scores short of the board until at most `capacity` are left; a
+    /// dropped player's total starts over if they play again in the same window
+    fn bound(&mut self, metric: Metric, capacity: usize) {
+        while self.scores.len() > capacity {
+            let worst = self
+                .scores
+                .iter()
+                .filter(|(player, _)| !self.entries.iter().any(|entry| &entry.player == *player))
+                .max_by(|a, b| metric.rank(*a.1, *b.1))
+                .map(|(player, _)| player.clone());
+            match worst {
+                Some(player) => Arc::make_mut(&mut self.scores).remove(&player),
+                None => break,
+            };
+        }
+    }
+
+    /// Every player with a score, best first; among equal scores, those on the board
+    /// stay ahead in its order
+    pub fn standings(&self, metric: Metric) -> Vec<LeaderboardEntry> {
+        let mut standings = self.entries.clone();
+        standings.extend(
+            self.scores
+                .iter()
+                .filter(|(player, _)| !self.entries.iter().any(|entry| &entry.player == *player))
+                .map(|(player, score)| LeaderboardEntry {
+                    player: player.clone(),
+                    score: *score,
+                }),
+        );
+        standings.sort_by(|a, b| metric.rank(a.score, b.score));
+        standings
+    }
+
+    /// Folds in another node's board for the same window; a board for an older window
+    /// (ie. yesterday's, from a node that hasn't rolled over yet) is dropped
+    fn merge(&mut self, metric: Metric, other: &Leaderboard, size: usize) {
+        match self.since.cmp(&other.since) {
+            Ordering::Greater => return,
+            Ordering::Less => *self = other.clone(),
+            Ordering::Equal => {
+                let scores = Arc::make_mut(&mut self.scores);
+                for (player, score) in other.scores.iter() {
+                    let score = scores
+                        .get(player)
+                        .map_or(*score, |current| metric.combine(*current, *score));
+                    scores.insert(player.clone(), score);
+                }
+                for entry in other.entries.iter() {
+                    if !self.entries.iter().any(|e| e.player == entry.player) {
+                        self.entries.push(entry.clone());
+                    }
+                }
+                for entry in self.entries.iter_mut() {
+                    entry.score = self.scores[&entry.player];
+                }
+                // Players short of the top on every node may make it once combined
+                self.entries = self.standings(metric);
+            }
+        }
+        self.sort(metric, size);
+    }
+
+    /// Re-ranks the board from every player's score, ie. after it was resized
+    fn rerank(&mut self, metric: Metric, size: usize) {
+        self.entries = self.standings(metric);
+        self.sort(metric, size);
+    }
+
+    fn sort(&mut self, metric: Metric, size: usize) {
+        // stable, so among equal scores whoever got there first stays ahead
+        self.entries.sort_by(|a, b| metric.rank(a.score, b.score));
+        self.entries.truncate(size);
+    }
+}
+
+/// Every leaderboard a node keeps, by window and then metric
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Leaderboards {
+    /// How many players each board keeps; unset (0) until `configure`d
+    #[serde(skip)]
+    size: usize,
+    #[serde(skip)]
+    metrics: Vec<Metric>,
+    #[serde(skip)]
+    event: Option<EventWindow>,
+    /// How many players' scores each daily and event board keeps
+    #[serde(skip)]
+    max_scores: usize,
+    boards: BTreeMap<Window, BTreeMap<Metric, Leaderboard>>,
+}
+
+impl Leaderboards {
+    pub fn configure(&mut self, config: &LeaderboardConfig) {
+        self.size = config.size;
+        self.metrics = config.metrics.clone();
+        self.event = config.event;
+        self.max_scores = config.max_scores;
+        for (window, boards) in self.boards.iter_mut() {
+            for (metric, board) in boards.iter_mut() {
+                board.rerank(*metric, config.size);
+                if *window != Window::AllTime {
+                    board.bound(*metric, config.max_scores);
+                }
+            }
+        }
+        self.roll();
+    }
+
+    pub fn get(&self, window: Window, metric: Metric) -> Option<&Leaderboard> {
+        self.boards.get(&window)?.get(&metric)
+    }
+
+    /// Records a player's score on every board for `metric` whose window is open
+    pub fn record(&mut self, metric: Metric, player: &str, score: u64) {
+        if !self.metrics.contains(&metric) {
+            return;
+        }
+        self.roll();
+        let now = now();
+
+        let mut windows = vec![Window::AllTime, Window::Daily];
+        if self
+            .event
+            .is_some_and(|event| (event.starts_at..event.ends_at).contains(&now))
+        {
+            windows.push(Window::Event);
+        }
+        for window in windows {
+            let since = self.window_start(window, now);
+            // All-time totals are kept in full; the other windows start over soon enough
+            let capacity = (window != Window::AllTime).then_some(self.max_scores);
+            self.boards
+                .entry(window)
+                .or_default()
+                .entry(metric)
+                .or_insert_with(|| Leaderboard {
+                    since,
+                    ..Default::default()
+                })
+                .record(metric, player, score, self.size, capacity);
+        }
+    }
+
+    /// Clears the boards whose window has moved on since they were last written
+    pub fn roll(&mut self) {
+        let now = now();
+        for window in [Window::Daily, Window::Event] {
+            if window == Window::Event && self.event.is_none() {
+                // keep the results of a past event around until another is configured
+                continue;
+            }
+            let since = self.window_start(window, now);
+            for board in self.boards.entry(window).or_default().values_mut() {
+                if board.since != since {
+                    board.since = since;
+                    board.entries.clear();
+                    board.scores = Default::default();
+                }
+            }
+        }
+    }
+
+    fn window_start(&self, window: Window, now: u64) -> u64 {
+        match window {
+            Window::AllTime => 0,
+            Window::Daily => now - now % (24 * 60 * 60),
+            Window::Event => self.event.map_or(0, |event| event.starts_at),
+        }
+    }
+
+    /// Drops implausible kills, items and secrets left behind by buggy clients
+    pub fn remove_outliers(&mut self) {
+        for boards in self.boards.values_mut() {
+            for metric in [Metric::Kills, Metric::Items, Metric::Secrets] {
+                if let Some(board) = boards.get_mut(&metric) {
+                    board
+                        .entries
+                        .retain(|entry| entry.score < MAX_PLAUSIBLE_SCORE);
+                    Arc::make_mut(&mut board.scores)
+                        .retain(|_, score| *score < MAX_PLAUSIBLE_SCORE);
+                }
+            }
+        }
+    }
+
+    /// Every player's score on every board, to be saved alongside the boards
+    pub fn scores(&self) -> BTreeMap<Window, BTreeMap<Metric, &BTreeMap<String, u64>>> {
+        self.boards
+            .iter()
+            .map(|(window, boards)| {
+                let scores = boards
+                    .iter()
+                    .map(|(metric, board)| (*metric, board.scores.as_ref()))
+                    .collect();
+                (*window, scores)
+            })
+            .collect()
+    }
+
+    /// Restores every player's score from the `leaderboard_scores` of a stats file; boards
+    /// saved before scores were kept start from their entries
+    pub fn restore_scores(&mut self, stats: &serde_json::Value) {
+        let mut saved: BTreeMap<Window, BTreeMap<Metric, BTreeMap<String, u64>>> = stats
+            .get("leaderboard_scores")
+            .and_then(|scores| serde_json::from_value(scores.clone()).ok())
+            .unwrap_or_default();
+        for (window, boards) in self.boards.iter_mut() {
+            for (metric, board) in boards.iter_mut() {
+                board.scores = Arc::new(
+                    match saved.get_mut(window).and_then(|saved| saved.remove(metric)) {
+                        Some(scores) => scores,
+                        None => board
+                            .entries
+                            .iter()
+                            .map(|entry| (entry.player.clone(), entry.score))
+                            .collect(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Imports the `[player, score]` leaderboards of stats files written before there
+    /// were windows, as all-time boards
+    pub fn import_legacy(&mut self, stats: &serde_json::Value) {
+        for (key, metric) in [
+            ("kills_leaderboard", Metric::Kills),
+            ("items_leaderboard", Metric::Items),
+            ("secrets_leaderboard", Metric::Secrets),
+        ] {
+            let Some(entries) = stats.get(key).and_then(|entries| entries.as_array()) else {
+                continue;
+            };
+            let board = self
+                .boards
+                .entry(Window::AllTime)
+                .or_default()
+                .entry(metric)
+                .or_default();
+            for entry in entries {
+                if let (Some(player), Some(score)) = (
+                    entry.get(0).and_then(|player| player.as_str()),
+                    entry.get(1).and_then(|score| score.as_u64()),
+                ) {
+                    board.record(metric, player, score, self.size, None);
+                }
+            }
+        }
+    }
+
+    /// Folds in another node's leaderboards
+    pub fn merge(&mut self, other: &Leaderboards) {
+        self.size = self.size.max(other.size);
+        for (window, boards) in other.boards.iter() {
+            for (metric, board) in boards.iter() {
+                self.boards
+                    .entry(*window)
+                    .or_default()
+                    .entry(*metric)
+                    .or_insert_with(|| Leaderboard {
+                        since: board.since,
+                        ..Default::default()
+                    })
+                    .merge(*metric, board, self.size);
+            }
+        }
+    }
+}
+
+/// A leaderboard ranked in full
+#[derive(Debug, Default)]
+pub struct Standings {
+    /// When the window started, in seconds since the epoch
+    pub since: u64,
+    /// Every player with a score, best first
+    pub entries: Vec<LeaderboardEntry>,
+    /// Where each player is in `entries`, by payment key hash
+    positions: HashMap<String, usize>,
+}
+
+impl Standings {
+    fn new(metric: Metric, board: &Leaderboard) -> Standings {
+        let entries = board.standings(metric);
+        let positions = entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.player.clone(), index))
+            .collect();
+        Standings {
+            since: board.since,
+            entries,
+            positions,
+        }
+    }
+
+    /// The player's index in `entries`, if they have a score
+    pub fn position(&self, player: &str) -> Option<usize> {
+        self.positions.get(player).copied()
+    }
+}
+
+/// Every node's leaderboards merged and ranked, overall and for each region; rebuilt by
+/// each maintenance sweep, so requests look players up instead of merging every node's
+/// scores themselves
+#[derive(Clone, Default)]
+pub struct LeaderboardIndex(Arc<Mutex<Index>>);
+
+#[derive(Default)]
+struct Index {
+    /// The overall boards, without the scores they were ranked from
+    boards: Leaderboards,
+    /// By region (`None` for every node), window and metric
+    standings: HashMap<(Option<String>, Window, Metric), Arc<Standings>>,
+}
+
+impl LeaderboardIndex {
+    /// Replaces the index with the leaderboards of every region, each merged across its nodes
+    pub fn rebuild(&self, regions: HashMap<String, Leaderboards>) {
+        let mut overall = Leaderboards::default();
+        let mut standings = HashMap::new();
+        for (region, leaderboards) in regions.iter() {
+            overall.merge(leaderboards);
+            standings.extend(Self::rank(Some(region), leaderboards));
+        }
+        standings.extend(Self::rank(None, &overall));
+
+        for board in overall
+            .boards
+            .values_mut()
+            .flat_map(|boards| boards.values_mut())
+        {
+            board.scores = Default::default();
+        }
+        *self.0.lock().unwrap() = Index {
+            boards: overall,
+            standings,
+        };
+    }
+
+    fn rank<'a>(
+        region: Option<&'a String>,
+        leaderboards: &'a Leaderboards,
+    ) -> impl Iterator<Item = ((Option<String>, Window, Metric), Arc<Standings>)> + 'a {
+        leaderboards
+            .boards
+            .iter()
+            .flat_map(move |(window, boards)| {
+                boards.iter().map(move |(metric, board)| {
+                    (
+                        (region.cloned(), *window, *metric),
+                        Arc::new(Standings::new(*metric, board)),
+                    )
+                })
+            })
+    }
+
+    /// A leaderboard merged across every node, or just those in `region`, as of the last sweep
+    pub fn standings(
+        &self,
+        region: Option<&str>,
+        window: Window,
+        metric: Metric,
+    ) -> Option<Arc<Standings>> {
+        self.0
+            .lock()
+            .unwrap()
+            .standings
+            .get(&(region.map(str::to_string), window, metric))
+            .cloned()
+    }
+
+    /// The top of every board, merged across every node, as of the last sweep
+    pub fn boards(&self) -> Leaderboards {
+        self.0.lock().unwrap().boards.clone()
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards...")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(metric: Metric, size: usize, scores: &[(&str, u64)]) -> Leaderboard {
+        let mut board = Leaderboard::default();
+        for (player, score) in scores {
+            board.record(metric, player, *score, size, None);
+        }
+        board
+    }
+
+    fn scores(entries: &[LeaderboardEntry]) -> Vec<(&str, u64)> {
+        entries
+            .iter()
+            .map(|entry| (entry.player.as_str(), entry.score))
+            .collect()
+    }
+
+    #[test]
+    fn totals_outside_the_top_keep_adding_up() {
+        let board = board(Metric::Bytes, 1, &[("a", 10), ("b", 6), ("b", 6)]);
+        assert_eq!(scores(&board.entries), vec![("b", 12)]);
+        assert_eq!(
+            scores(&board.standings(Metric::Bytes)),
+            vec![("b", 12), ("a", 10)]
+        );
+    }
+
+    #[test]
+    fn single_game_metrics_keep_the_best() {
+        let board = board(
+            Metric::FastestLevel,
+            2,
+            &[("a", 900), ("a", 1200), ("b", 700)],
+        );
+        assert_eq!(scores(&board.entries), vec![("b", 700), ("a", 900)]);
+    }
+
+    #[test]
+    fn totals_short_of_the_top_on_every_node_can_top_the_merged_board() {
+        let mut merged = board(Metric::GamesPlayed, 1, &[("a", 3), ("c", 2)]);
+        merged.merge(
+            Metric::GamesPlayed,
+            &board(Metric::GamesPlayed, 1, &[("b", 3), ("c", 2)]),
+            1,
+        );
+        assert_eq!(scores(&merged.entries), vec![("c", 4)]);
+        assert_eq!(
+            scores(&merged.standings(Metric::GamesPlayed)),
+            vec![("c", 4), ("a", 3), ("b", 3)]
+        );
+    }
+
+    #[test]
+    fn scores_survive_a_save() {
+        let mut leaderboards = Leaderboards::default();
+        leaderboards.configure(&LeaderboardConfig {
+            size: 1,
+            ..Default::default()
+        });
+        leaderboards.record(Metric::Bytes, "a", 10);
+        leaderboards.record(Metric::Bytes, "b", 6);
+
+        let saved = serde_json::json!({
+            "leaderboards": serde_json::to_value(&leaderboards).unwrap(),
+            "leaderboard_scores": serde_json::to_value(leaderboards.scores()).unwrap()
+        });
+        let mut restored: Leaderboards =
+            serde_json::from_value(saved["leaderboards"].clone()).unwrap();
+        restored.restore_scores(&saved);
+
+        let board = restored.get(Window::AllTime, Metric::Bytes).unwrap();
+        assert_eq!(
+            scores(&board.standings(Metric::Bytes)),
+            vec![("a", 10), ("b", 6)]
+        );
+    }
+
+    fn leaderboards(size: usize, max_scores: usize, scores: &[(&str, u64)]) -> Leaderboards {
+        let mut leaderboards = Leaderboards::default();
+        leaderboards.configure(&LeaderboardConfig {
+            size,
+            max_scores,
+            ..Default::default()
+        });
+        for (player, score) in scores {
+            leaderboards.record(Metric::Kills, player, *score);
+        }
+        leaderboards
+    }
+
+    #[test]
+    fn daily_scores_are_bounded() {
+        let leaderboards = leaderboards(1, 2, &[("a", 5), ("b", 3), ("c", 4)]);
+        let daily = leaderboards.get(Window::Daily, Metric::Kills).unwrap();
+        assert_eq!(
+            scores(&daily.standings(Metric::Kills)),
+            vec![("a", 5), ("c", 4)]
+        );
+        let all_time = leaderboards.get(Window::AllTime, Metric::Kills).unwrap();
+        assert_eq!(all_time.scores.len(), 3);
+    }
+
+    #[test]
+    fn bounds_never_drop_the_board() {
+        let leaderboards = leaderboards(2, 1, &[("a", 5), ("b", 3)]);
+        let daily = leaderboards.get(Window::Daily, Metric::Kills).unwrap();
+        assert_eq!(scores(&daily.entries), vec![("a", 5), ("b", 3)]);
+    }
+
+    #[test]
+    fn index_ranks_every_region_and_all_of_them() {
+        let index = LeaderboardIndex::default();
+        index.rebuild(HashMap::from([
+            ("eu".to_string(), leaderboards(1, 10, &[("a", 5), ("b", 3)])),
+            ("us".to_string(), leaderboards(1, 10, &[("c", 4)])),
+        ]));
+
+        let overall = index
+            .standings(None, Window::AllTime, Metric::Kills)
+            .unwrap();
+        assert_eq!(scores(&overall.entries), vec![("a", 5), ("c", 4), ("b", 3)]);
+        assert_eq!(overall.position("b"), Some(2));
+        assert_eq!(overall.position("d"), None);
+
+        let us = index
+            .standings(Some("us"), Window::AllTime, Metric::Kills)
+            .unwrap();
+        assert_eq!(scores(&us.entries), vec![("c", 4)]);
+        assert!(index
+            .standings(Some("ap"), Window::AllTime, Metric::Kills)
+            .is_none());
+
+        let boards = index.boards();
+        let board = boards.get(Window::AllTime, Metric::Kills).unwrap();
+        assert_eq!(scores(&board.entries), vec![("a", 5)]);
+        assert!(board.scores.is_empty());
+    }
+}
@@ -1,9 +1,14 @@
 use std::fmt;
 
+pub mod error;
 pub mod game_state;
 pub mod hydra;
+pub mod idempotency;
+pub mod leaderboard;
 pub mod node;
 pub mod player;
+pub mod player_directory;
+pub mod submission;
 pub mod tx_builder;
 
 pub fn format_hex<T: AsRef<[u8]>>(data: T, f: &mut fmt::Formatter) -> fmt::Result {
@@ -2,52 +2,77 @@ use std::{
     collections::HashMap,
     fs::{self, File},
     path::Path,
-    sync::{atomic::AtomicBool, Arc},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::SCRIPT_CBOR;
 use anyhow::Error;
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
 use hex::FromHex;
 use pallas::{
-    codec::{minicbor::decode, utils::KeepRaw},
+    codec::minicbor::decode,
     crypto::key::ed25519::SecretKey,
     ledger::{
         addresses::Address,
-        primitives::{
-            babbage::{PseudoScript, PseudoTransactionOutput},
-            conway::{
-                NativeScript, PlutusData, PseudoDatumOption, PseudoPostAlonzoTransactionOutput,
-            },
-        },
-        traverse::MultiEraTx,
+        primitives::conway::{PlutusData, PseudoDatumOption},
+        traverse::{MultiEraOutput, MultiEraTx},
     },
-    txbuilder::{BuildBabbage, BuiltTransaction, Output, ScriptKind, StagingTransaction},
+    txbuilder::BuiltTransaction,
 };
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::sync::mpsc::UnboundedSender;
-use tracing::{debug, warn};
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
 
 use super::{
+    error::ControlPlaneError,
     game_state::GameState,
     hydra::{
         hydra_message::HydraData,
-        hydra_socket::HydraSocket,
+        hydra_socket::{HealthReport, HydraSocket, SocketHealth},
         messages::{new_tx::NewTx, tx_valid::TxValid},
+        state::NodeHandle,
     },
+    leaderboard::{Leaderboards, Metric},
     player::Player,
-    tx_builder::TxBuilder,
+    submission::{PendingSubmission, SubmissionTracker, WaitFor},
+    tx_builder::{Era, TxBuilder},
+};
+use crate::{
+    model::hydra::{
+        decode_failures::DecodeFailures,
+        event_seq::EventSeq,
+        head_setup::{HeadSetup, HeadSetupReport},
+        messages::{
+            committed::Committed, greetings::Greetings, head_is_initializing::HeadIsInitializing,
+            head_is_open::HeadIsOpen, peer_connected::PeerConnected,
+            peer_disconnected::PeerDisconnected,
+        },
+        utxo::{find_reference_script, ScriptType, UTxO, UtxoSet},
+        utxo_cache::UtxoCache,
+    },
+    Config, ExpiryPolicy, NodeConfig, VersionRange, SCRIPT_ADDRESS, SCRIPT_CBOR,
 };
-use crate::{model::hydra::utxo::UTxO, NodeConfig, SCRIPT_ADDRESS};
 
-#[derive(Clone, Serialize)]
+/// How many expired games to end in a single transaction, to stay within its execution budget
+const RECLAIM_BATCH: usize = 2;
+
+#[derive(Clone, Serialize, ToSchema)]
 pub struct Node {
     #[serde(rename = "id")]
     pub head_id: Option<String>,
+    pub head_status: HeadStatus,
+    pub hydra_node_version: Option<String>,
+    /// The hydra verification key of the node's party, hex encoded
+    pub party: Option<String>,
+    /// Whether `hydra_node_version` is one games may be placed on
+    pub version_compatible: bool,
     #[serde(rename = "total")]
     pub stats: NodeStats,
     pub stats_file: Option<String>,
@@ -55,7 +80,17 @@ pub struct Node {
     pub max_players: usize,
     pub persisted: bool,
     pub reserved: bool,
+    #[schema(value_type = bool)]
     pub online: Arc<AtomicBool>,
+    #[schema(value_type = HealthReport)]
+    pub health: SocketHealth,
+    pub drift: Drift,
+    /// The other parties' nodes in the head, keyed by peer; the configured ones are always
+    /// listed, connected or not
+    pub peers: HashMap<String, PeerStatus>,
+    #[schema(value_type = Option<HeadSetupReport>)]
+    pub setup: Option<HeadSetup>,
+    pub script_ref: ScriptRefState,
 
     #[serde(skip)]
     pub local_connection: ConnectionInfo,
@@ -67,34 +102,124 @@ pub struct Node {
     pub players: Vec<Player>,
     #[serde(skip)]
     pub tx_builder: TxBuilder,
+    /// The era set in the node's config, which wins over the one its version implies
+    #[serde(skip)]
+    pub configured_era: Option<Era>,
+    #[serde(skip)]
+    pub placement: Arc<Mutex<()>>,
+    #[serde(skip)]
+    pub submissions: SubmissionTracker,
+    #[serde(skip)]
+    pub utxo_cache: UtxoCache,
+    #[serde(skip)]
+    pub decode_failures: DecodeFailures,
+    #[serde(skip)]
+    pub event_seq: EventSeq,
+    #[serde(skip)]
+    pub expected_peers: Vec<String>,
+    #[serde(skip)]
+    pub hydra_versions: VersionRange,
+    #[serde(skip)]
+    pub initialization_timeout: Duration,
+    #[serde(skip)]
+    pub submission_timeout: Duration,
+    #[serde(skip)]
+    pub expiry: ExpiryPolicy,
+    /// How long a transaction may wait for a snapshot before it's considered orphaned
+    #[serde(skip)]
+    pub pending_ttl: Duration,
 }
 
-#[derive(Clone, Serialize)]
-pub struct ConnectionInfo {
-    pub host: String,
-    pub port: u32,
-    pub secure: bool,
+/// Where the node is with the reference script games are validated against
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ScriptRefState {
+    /// The head isn't open, or we haven't looked yet
+    Unknown,
+    /// A deployment transaction is on its way; `utxo` is set once it's submitted
+    Deploying {
+        utxo: Option<String>,
+    },
+    Deployed {
+        utxo: String,
+    },
+    Failed {
+        error: String,
+    },
 }
 
-#[derive(Serialize)]
-pub struct NodeSummary(pub Node);
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct PeerStatus {
+    pub connected: bool,
+    pub connected_at: Option<String>,
+    pub disconnected_at: Option<String>,
+}
 
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone)]
-pub struct LeaderboardEntry(String, u64);
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub enum HeadStatus {
+    Unknown,
+    Idle,
+    Initializing,
+    Open,
+    Closed,
+    FanoutPossible,
+    Final,
+}
 
-impl PartialOrd for LeaderboardEntry {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+impl From<&str> for HeadStatus {
+    fn from(value: &str) -> Self {
+        match value {
+            "Idle" => HeadStatus::Idle,
+            "Initializing" => HeadStatus::Initializing,
+            "Open" => HeadStatus::Open,
+            "Closed" => HeadStatus::Closed,
+            "FanoutPossible" => HeadStatus::FanoutPossible,
+            "Final" => HeadStatus::Final,
+            _ => HeadStatus::Unknown,
+        }
     }
 }
 
-impl Ord for LeaderboardEntry {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.1.cmp(&other.1)
-    }
+/// The parts of a node needed to talk to its hydra-node.
+///
+/// Cloned out of the node so remote I/O can happen without holding the node lock.
+#[derive(Clone)]
+pub struct NodeClient {
+    pub local_connection: ConnectionInfo,
+    pub socket: HydraSocket,
+    pub tx_builder: TxBuilder,
+    /// Serializes game creation on this node, so two concurrent games never spend the same admin UTxO
+    pub placement: Arc<Mutex<()>>,
+    pub submissions: SubmissionTracker,
+    pub utxo_cache: UtxoCache,
+    pub decode_failures: DecodeFailures,
+    /// How long to wait for the head to act on a transaction we submitted
+    pub submission_timeout: Duration,
+}
+
+/// Differences found between the players we track and the games in the head's UTxO set
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct Drift {
+    /// Games in the head we weren't tracking; these are now tracked
+    pub untracked: usize,
+    /// Tracked players whose game UTxO isn't in the head
+    pub missing: usize,
+    /// Tracked players whose game UTxO differs from the one in the head
+    pub mismatched: usize,
+    pub checked_at: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub host: String,
+    pub port: u32,
+    pub secure: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, ToSchema)]
+pub struct NodeSummary(pub Node);
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct NodeStats {
     #[serde(default)]
     pub online_nodes: usize,
@@ -107,19 +232,63 @@ pub struct NodeStats {
 
     pub kills: HashMap<String, u64>,
     pub total_kills: u64,
-    pub kills_leaderboard: Vec<LeaderboardEntry>,
     pub items: HashMap<String, u64>,
     pub total_items: u64,
-    pub items_leaderboard: Vec<LeaderboardEntry>,
     pub secrets: HashMap<String, u64>,
     pub total_secrets: u64,
-    pub secrets_leaderboard: Vec<LeaderboardEntry>,
+    /// A `Leaderboard` for each window (`all_time`, `daily`, `event`) and metric
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub leaderboards: Leaderboards,
 
     pub player_play_time: HashMap<String, Vec<u128>>,
     pub total_play_time: u128,
 
+    /// The seq of the last SnapshotConfirmed folded into these stats, so a restart skips the
+    /// history they already cover; anything after it (ie. transactions still waiting for a
+    /// snapshot, which aren't saved) is replayed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_seq: Option<u64>,
+    /// The head `snapshot_seq` belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_head_id: Option<String>,
+
+    /// Transactions dropped from `pending` after waiting too long for a snapshot
+    #[serde(default)]
+    pub orphaned_transactions: u64,
+    /// How many transactions are waiting for a snapshot
+    #[serde(skip_deserializing)]
+    pub pending_transactions: usize,
+    /// The transactions waiting for a snapshot, by tx id; only changed through `NodeStats`,
+    /// which keeps `pending_transactions` in step
     #[serde(skip)]
-    pub pending_transactions: HashMap<Vec<u8>, StateUpdate>,
+    pub pending: HashMap<Vec<u8>, PendingTransaction>,
+}
+
+/// What a player achieved in a finished game
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct PlayerStats {
+    pub kills: u64,
+    pub items: u64,
+    pub secrets: u64,
+    pub play_time: u128,
+}
+
+/// A game placed by `add_player`
+#[derive(Clone, Debug)]
+pub struct NewGame {
+    pub player_utxo: String,
+    pub player_utxo_datum_hex: String,
+    /// Needed to end the game early, see `Player::end_token`
+    pub end_token: String,
+}
+
+/// A transaction seen in `TxValid`, waiting for a snapshot to confirm it
+#[derive(Clone, Debug)]
+pub struct PendingTransaction {
+    pub bytes: u64,
+    pub updates: Vec<StateUpdate>,
+    pub seen_at: Instant,
 }
 
 #[derive(Clone, Debug)]
@@ -149,7 +318,11 @@ impl TryInto<SecretKey> for KeyEnvelope {
 }
 
 impl Node {
-    pub async fn try_new(config: &NodeConfig, writer: &UnboundedSender<HydraData>) -> Result<Self> {
+    pub async fn try_new(
+        config: &NodeConfig,
+        global: &Config,
+        writer: &UnboundedSender<HydraData>,
+    ) -> Result<Self> {
         let (local_connection, remote_connection) = ConnectionInfo::from_config(config)?;
 
         let admin_key: KeyEnvelope = serde_json::from_reader(
@@ -157,15 +330,21 @@ impl Node {
         )
         .context("unable to parse key file")?;
 
-        let mut stats = if config.stats_file.is_some()
+        let (mut stats, contents) = if config.stats_file.is_some()
             && Path::new(config.stats_file.as_ref().unwrap()).exists()
         {
             let contents = fs::read_to_string(config.stats_file.as_ref().unwrap())
                 .context("expected a stats file")?;
-            serde_json::from_str(contents.as_str())?
+            (serde_json::from_str(contents.as_str())?, Some(contents))
         } else {
-            NodeStats::new()
+            (NodeStats::new(), None)
         };
+        if let Some(contents) = contents {
+            let saved = serde_json::from_str(contents.as_str())?;
+            stats.leaderboards.restore_scores(&saved);
+            stats.leaderboards.import_legacy(&saved);
+        }
+        stats.leaderboards.configure(&global.leaderboards);
         // Collapse any previous players into total time
         for (_, kills) in stats.kills.drain() {
             stats.total_kills += if kills < 10000 { kills } else { 0 };
@@ -180,21 +359,26 @@ impl Node {
             stats.total_play_time += play_times.iter().sum::<u128>();
         }
         // Remove any buggy top scores
-        for leaderboard in &mut [
-            &mut stats.kills_leaderboard,
-            &mut stats.items_leaderboard,
-            &mut stats.secrets_leaderboard,
-        ] {
-            leaderboard.retain(|entry| entry.1 < 10000);
+        stats.leaderboards.remove_outliers();
+
+        let event_seq = EventSeq::resume(stats.snapshot_head_id.clone(), stats.snapshot_seq);
+        let mut tx_builder = TxBuilder::new(admin_key.try_into()?);
+        if let Some(era) = config.era {
+            tx_builder.era = era;
         }
 
         let socket = HydraSocket::new(
             local_connection.to_websocket_url().as_str(),
             local_connection.to_authority(),
             writer,
+            &global.socket,
         );
-        let node = Node {
+        let mut node = Node {
             head_id: None,
+            head_status: HeadStatus::Unknown,
+            hydra_node_version: None,
+            party: None,
+            version_compatible: true,
             local_connection,
             remote_connection,
             stats,
@@ -204,81 +388,221 @@ impl Node {
             persisted: config.persisted,
             reserved: config.reserved,
             online: socket.online.clone(),
+            health: socket.health.clone(),
+            drift: Drift::default(),
+            peers: HashMap::new(),
+            setup: None,
+            script_ref: ScriptRefState::Unknown,
 
             players: Vec::new(),
             socket,
-            tx_builder: TxBuilder::new(admin_key.try_into()?),
+            tx_builder,
+            configured_era: config.era,
+            placement: Arc::new(Mutex::new(())),
+            submissions: SubmissionTracker::default(),
+            utxo_cache: UtxoCache::default(),
+            decode_failures: DecodeFailures::default(),
+            event_seq,
+            expected_peers: config.peers.clone(),
+            hydra_versions: global.hydra_versions.clone(),
+            initialization_timeout: Duration::from_secs(global.initialization_timeout_secs),
+            submission_timeout: Duration::from_millis(global.submission_timeout_ms),
+            expiry: global.expiry_policy(config),
+            pending_ttl: Duration::from_secs(global.pending_transaction_ttl_secs),
         };
 
+        node.reset_peers();
         node.start_listen();
         Ok(node)
     }
 
-    pub async fn create_script_ref(&self) -> Result<String, Error> {
-        let utxos = self.fetch_utxos().await.context("Failed to fetch utxos")?;
-        let admin_utxos = self.tx_builder.find_admin_utxos(utxos);
-        if admin_utxos.is_empty() {
-            bail!("No admin UTxOs found");
-        };
-
-        let input_utxo = admin_utxos.first().unwrap();
+    pub fn client(&self) -> NodeClient {
+        NodeClient {
+            local_connection: self.local_connection.clone(),
+            socket: self.socket.clone(),
+            tx_builder: self.tx_builder.clone(),
+            placement: self.placement.clone(),
+            submissions: self.submissions.clone(),
+            utxo_cache: self.utxo_cache.clone(),
+            decode_failures: self.decode_failures.clone(),
+            submission_timeout: self.submission_timeout,
+        }
+    }
 
-        let script_address = Address::from_bech32(SCRIPT_ADDRESS).unwrap();
+    /// Creates a new game for `player` on the node behind `handle`.
+    ///
+    /// The node lock is only taken to read and update in-memory state; fetching UTxOs and
+    /// submitting the transaction happen without it, so other routes and the event loop
+    /// are never stuck behind a slow head.
+    ///
+    /// Once sent, waits for the head to get as far as `wait_for` with the transaction; if it
+    /// doesn't in time, the player is removed again and an error returned. `sent` is given
+    /// the game as soon as the transaction is sent, as it may exist from then on even if
+    /// waiting fails.
+    pub async fn add_player(
+        handle: &NodeHandle,
+        mut player: Player,
+        collateral_addr: Address,
+        wait_for: WaitFor,
+        sent: impl FnOnce(&NewGame),
+    ) -> Result<NewGame> {
+        let client = handle.read().await.client();
+        let placement = client.placement.lock().await;
 
-        let bytes = hex::decode(SCRIPT_CBOR).unwrap();
+        // Expired games are ended and reclaimed by the maintenance task
+        let utxos = client.utxos().await?;
 
-        let tx = StagingTransaction::new()
-            .input(input_utxo.clone().into())
-            .output(Output::new(script_address, 0).set_inline_script(ScriptKind::PlutusV2, bytes))
-            .output(Output::new(
-                input_utxo.clone().address,
-                input_utxo.value.get("lovelace").unwrap().to_owned(),
-            ))
-            .fee(0)
-            .build_babbage_raw()?;
+        let (new_game_tx, player_utxo_datum) =
+            client
+                .tx_builder
+                .build_new_game_state(&player, utxos, collateral_addr)?;
+        let player_utxo = hex::encode(new_game_tx.tx_hash.0) + "#0";
+        let end_token = client.tx_builder.end_token(&player_utxo);
+        let game = NewGame {
+            player_utxo,
+            player_utxo_datum_hex: hex::encode(player_utxo_datum),
+            end_token: end_token.clone(),
+        };
 
-        let signed_tx = tx.sign(self.tx_builder.admin_key.clone().into())?;
+        let pkh = player.pkh.clone();
+        player.end_token = Some(end_token);
+        {
+            let mut node = handle.write().await;
+            node.stats.total_games += 1;
+            node.players.push(player);
+        }
+        let result = match client.submit(new_game_tx, wait_for).await {
+            Ok(submission) => {
+                drop(placement);
+                sent(&game);
+                client.settle(submission).await
+            }
+            Err(e) => Err(e),
+        };
 
-        let utxo = hex::encode(signed_tx.tx_hash.0) + "#0";
+        if let Err(e) = result {
+            handle.write().await.remove_pending_player(&pkh);
+            return Err(e);
+        }
 
-        let message: String = NewTx::new(signed_tx)?.into();
+        Ok(game)
+    }
 
-        self.send(message).await?;
+    /// Ends the game `pkh` is playing on the node behind `handle`, spending its game UTxO;
+    /// `end_token` must be the one the game was created with.
+    ///
+    /// Once the head has got as far as `wait_for` with the transaction, the player's stats
+    /// are folded into the totals and saved, and their slot is released.
+    pub async fn end_game(
+        handle: &NodeHandle,
+        pkh: &[u8],
+        end_token: &str,
+        wait_for: WaitFor,
+    ) -> Result<(String, PlayerStats)> {
+        let client = handle.read().await.client();
+        let placement = client.placement.lock().await;
+
+        let (game_utxo, script_ref) = {
+            let node = handle.read().await;
+            let player = node
+                .players
+                .iter()
+                .find(|player| player.pkh == pkh)
+                .ok_or_else(|| ControlPlaneError::GameNotFound(hex::encode(pkh)))?;
+            if player.end_token.as_deref() != Some(end_token) {
+                return Err(ControlPlaneError::NotGameOwner(hex::encode(pkh)).into());
+            }
+            let game_utxo = player
+                .utxo
+                .clone()
+                .ok_or_else(|| ControlPlaneError::GameNotFound(hex::encode(pkh)))?;
+            (game_utxo, node.deployed_script_ref()?)
+        };
 
-        Ok(utxo)
+        let utxos = client.utxos().await?;
+        let script_ref = find_utxo(&utxos, &script_ref)
+            .cloned()
+            .ok_or(ControlPlaneError::ScriptRefMissing)?;
+        let cost_model = client.fetch_cost_model().await?;
+
+        let tx =
+            client
+                .tx_builder
+                .build_end_games(vec![game_utxo], utxos, script_ref, cost_model)?;
+        let tx_id = hex::encode(tx.tx_hash.0);
+
+        let submission = client.submit(tx, wait_for).await?;
+        drop(placement);
+        client.settle(submission).await?;
+
+        let mut node = handle.write().await;
+        node.players.retain(|player| player.pkh != pkh);
+        let stats = node.stats.finish_game(&hex::encode(pkh));
+        node.stats.save(node.stats_file.clone());
+        Ok((tx_id, stats))
     }
 
-    pub async fn find_script_ref(&self) -> Option<UTxO> {
-        let utxos = self
-            .fetch_utxos()
-            .await
-            .context("Failed to fetch utxos")
-            .ok()?;
-        utxos.into_iter().find(|utxo| {
-            utxo.reference_script.is_some() && utxo.address.to_bech32().unwrap() == SCRIPT_ADDRESS
-        })
+    /// Spends the game UTxOs of expired players back to the admin, a few per transaction;
+    /// games that have already left the head are skipped
+    pub async fn reclaim_games(handle: NodeHandle, game_utxos: Vec<UTxO>) {
+        let client = handle.read().await.client();
+        let result = async {
+            for batch in game_utxos.chunks(RECLAIM_BATCH) {
+                let placement = client.placement.lock().await;
+                let script_ref = handle.read().await.deployed_script_ref()?;
+
+                let utxos = client.utxos().await?;
+                let batch: Vec<UTxO> = batch
+                    .iter()
+                    .filter(|game| find_utxo(&utxos, &game.to_string()).is_some())
+                    .cloned()
+                    .collect();
+                if batch.is_empty() {
+                    continue;
+                }
+                let script_ref = find_utxo(&utxos, &script_ref)
+                    .cloned()
+                    .ok_or(ControlPlaneError::ScriptRefMissing)?;
+                let cost_model = client.fetch_cost_model().await?;
+
+                let tx = client
+                    .tx_builder
+                    .build_end_games(batch, utxos, script_ref, cost_model)?;
+                let submission = client.submit(tx, WaitFor::Valid).await?;
+                drop(placement);
+                client.settle(submission).await?;
+            }
+            Ok::<(), Error>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            warn!(
+                "failed to reclaim expired games on {}: {:?}",
+                client.local_connection.to_authority(),
+                e
+            );
+        }
     }
 
-    pub async fn add_player(
-        &mut self,
-        player: Player,
-        collateral_addr: Address,
-    ) -> Result<(String, String)> {
-        let expired_utxos = self.cleanup_players();
-        let utxos = self.fetch_utxos().await.context("Failed to fetch utxos")?;
-
-        let (new_game_tx, player_utxo_datum) =
-            self.tx_builder
-                .build_new_game_state(&player, utxos, expired_utxos, collateral_addr)?;
-        let player_utxo = hex::encode(new_game_tx.tx_hash.0) + "#0";
-
-        let message: String = NewTx::new(new_game_tx)?.into();
-
-        self.stats.total_games += 1;
-        self.players.push(player);
-        self.send(message).await?;
+    /// The script ref games are validated against, if it's deployed
+    fn deployed_script_ref(&self) -> Result<String> {
+        match &self.script_ref {
+            ScriptRefState::Deployed { utxo } => Ok(utxo.clone()),
+            _ => Err(ControlPlaneError::ScriptRefMissing.into()),
+        }
+    }
 
-        Ok((player_utxo, hex::encode(player_utxo_datum)))
+    /// Undoes `add_player` for a game whose transaction never made it into the head
+    fn remove_pending_player(&mut self, pkh: &[u8]) {
+        if let Some(index) = self
+            .players
+            .iter()
+            .position(|player| player.pkh == pkh && player.utxo.is_none())
+        {
+            self.players.remove(index);
+            self.stats.total_games = self.stats.total_games.saturating_sub(1);
+        }
     }
 
     pub fn start_listen(&self) {
@@ -286,147 +610,430 @@ impl Node {
         tokio::spawn(async move { socket.listen() });
     }
 
-    pub async fn send(&self, message: String) -> Result<()> {
-        self.socket.send(message).await
+    pub fn add_transaction(&mut self, transaction: TxValid) -> Result<()> {
+        let bytes = transaction.cbor.as_slice();
+        let tx = MultiEraTx::decode(bytes).context("Failed to decode transaction")?;
+
+        if let Some(era) =
+            Era::from_tx_type(&transaction.tx_type).filter(|_| self.configured_era.is_none())
+        {
+            self.tx_builder.era = era;
+        }
+
+        self.utxo_cache.apply_transaction(&transaction.tx_id, &tx);
+
+        let tx_id = hex::encode(&transaction.tx_id);
+        let timestamp: u128 = transaction
+            .timestamp
+            .parse::<DateTime<Utc>>()
+            .context("timestamp")?
+            .timestamp() as u128;
+
+        // A single transaction may move several games at once, so keep track of where each
+        // game output sits in the transaction; that's the index of the UTxO it creates.
+        let script_outputs = tx
+            .outputs()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, output)| is_script_output(output))
+            .map(|(index, output)| (index as u64, output))
+            .collect::<Vec<_>>();
+
+        let mut games = vec![];
+        for (index, output) in script_outputs {
+            let datum = match output.datum() {
+                Some(PseudoDatumOption::Data(datum)) => datum,
+                // If there's no datum, or a datum hash, it's not a game (ie. the script ref)
+                _ => continue,
+            };
+
+            let data = match decode::<PlutusData>(datum.0.raw_cbor()) {
+                Ok(data) => data,
+                Err(_) => bail!("Failed to deserialize datum at output {}", index),
+            };
+
+            let game_state_result: Result<GameState> = data.try_into();
+            let game_state = game_state_result
+                .with_context(|| format!("invalid game state at output {}", index))?;
+            let utxo = UTxO::try_from_pallas(tx_id.as_str(), index, &output)
+                .with_context(|| format!("invalid utxo at output {}", index))?;
+
+            games.push((utxo, game_state));
+        }
+
+        if games.is_empty() {
+            // Unrelated transaction, ie. an admin transaction
+            return Ok(());
+        }
+
+        // Split the transaction size evenly between the games it moved
+        let bytes_per_game = transaction.cbor.len() as u64 / games.len() as u64;
+        let mut updates = vec![];
+        for (utxo, game_state) in games {
+            let player = match self
+                .players
+                .iter()
+                .position(|player| player.pkh == game_state.owner)
+            {
+                Some(index) => &mut self.players[index],
+                None => {
+                    // We must have restarted, or the player was created through another control plane; create the player now
+                    warn!(
+                        "Unrecognized player, adding: {}",
+                        hex::encode(&game_state.owner)
+                    );
+                    self.players.push(Player {
+                        pkh: game_state.owner.clone(),
+                        utxo: None,
+                        game_state: Some(game_state.clone()),
+                        utxo_time: 0,
+                        started_at: timestamp,
+                        end_token: None,
+                    });
+                    self.players.last_mut().expect("Just added")
+                }
+            };
+
+            player.utxo = Some(utxo);
+            player.utxo_time = timestamp;
+
+            updates.push(player.generate_state_update(bytes_per_game, game_state));
+        }
+
+        self.stats.add_pending(
+            transaction.tx_id,
+            PendingTransaction {
+                bytes: transaction.cbor.len() as u64,
+                updates,
+                seen_at: Instant::now(),
+            },
+        );
+
+        Ok(())
     }
 
-    pub async fn fetch_utxos(&self) -> Result<Vec<UTxO>> {
-        let request_url = self.local_connection.to_http_url() + "/snapshot/utxo";
-        let response = reqwest::get(&request_url).await.context("http error")?;
+    /// Whether new games may be placed on this node
+    pub fn is_available(&self) -> bool {
+        self.socket.online.load(Ordering::SeqCst)
+            && self.version_compatible
+            && matches!(self.script_ref, ScriptRefState::Deployed { .. })
+            // Snapshots can't be confirmed without every party, so games would freeze
+            && self.peers.values().all(|peer| peer.connected)
+            // Peers we weren't told about still have to be there
+            && self.setup.as_ref().is_none_or(|setup| {
+                self.peers.values().filter(|peer| peer.connected).count() + 1
+                    >= setup.party_count()
+            })
+    }
 
-        let body = response
-            .json::<HashMap<String, Value>>()
-            .await
-            .context("http error")?;
+    /// A new connection to the node; what we knew about its peers is stale, and the
+    /// history it replays brings them back up to date
+    pub fn connected(&mut self) {
+        self.event_seq.connected();
+        self.reset_peers();
+    }
 
-        let utxos = body
+    fn reset_peers(&mut self) {
+        self.peers = self
+            .expected_peers
             .iter()
-            .map(|(key, value)| UTxO::try_from_value(key, value))
-            .collect::<Result<Vec<UTxO>>>()?;
+            .map(|peer| (peer.clone(), PeerStatus::default()))
+            .collect();
+    }
 
-        Ok(utxos)
+    pub fn head_initializing(&mut self, head_is_initializing: HeadIsInitializing) {
+        info!(
+            "head {} initializing on {} with {} parties",
+            head_is_initializing.head_id,
+            self.local_connection.to_authority(),
+            head_is_initializing.parties.len()
+        );
+        self.head_status = HeadStatus::Initializing;
+        self.head_id = Some(head_is_initializing.head_id.clone());
+        self.setup = Some(HeadSetup::new(
+            &head_is_initializing,
+            self.initialization_timeout,
+        ));
     }
 
-    pub fn add_transaction(&mut self, transaction: TxValid) -> Result<()> {
-        let bytes = transaction.cbor.as_slice();
-        let tx = MultiEraTx::decode(bytes).context("Failed to decode transaction")?;
+    pub fn committed(&mut self, committed: Committed) {
+        self.decode_failures.record(
+            &self.local_connection.to_authority(),
+            "Committed",
+            &committed.skipped,
+        );
+        match self.setup.as_mut() {
+            Some(setup) => setup.committed(&committed),
+            None => warn!(
+                "commit to {} on {} before the head was initialized",
+                committed.head_id,
+                self.local_connection.to_authority()
+            ),
+        }
+    }
+
+    pub fn head_opened(&mut self, head_is_open: HeadIsOpen) {
+        self.decode_failures.record(
+            &self.local_connection.to_authority(),
+            "HeadIsOpen",
+            &head_is_open.skipped,
+        );
+        self.head_status = HeadStatus::Open;
+        self.utxo_cache.replace(head_is_open.utxos);
+        if let Some(setup) = self.setup.as_mut() {
+            setup.opened(&head_is_open.timestamp);
+        }
+        if self.head_id.is_none() {
+            info!(
+                "updating node {:?} with head_id {:?}",
+                self.local_connection.to_authority(),
+                head_is_open.head_id
+            );
+            self.head_id = Some(head_is_open.head_id);
+        }
+    }
 
-        let tx = tx.as_babbage().context("Invalid babbage era tx")?;
+    pub fn peer_connected(&mut self, peer_connected: PeerConnected) {
+        info!(
+            "peer {} connected to {}",
+            peer_connected.peer,
+            self.local_connection.to_authority()
+        );
+        self.update_peer(peer_connected.peer, true, peer_connected.timestamp);
+    }
 
-        let outputs = &tx.transaction_body.outputs;
-        let script_outputs = outputs
-            .iter()
-            .filter(|output| match output {
-                PseudoTransactionOutput::PostAlonzo(output) => {
-                    let bytes: Vec<u8> = output.address.clone().into();
-                    let address = match Address::from_bytes(bytes.as_slice()) {
-                        Ok(address) => address,
-                        Err(_) => return false,
-                    };
-                    // unwrapping here because it came from hydra, so it is valid
-                    let address = address.to_bech32().unwrap();
-
-                    address.as_str() == SCRIPT_ADDRESS
+    pub fn peer_disconnected(&mut self, peer_disconnected: PeerDisconnected) {
+        warn!(
+            "peer {} disconnected from {}",
+            peer_disconnected.peer,
+            self.local_connection.to_authority()
+        );
+        self.update_peer(peer_disconnected.peer, false, peer_disconnected.timestamp);
+    }
+
+    /// Records a peer (dis)connecting at `timestamp`
+    pub fn update_peer(&mut self, peer: String, connected: bool, timestamp: String) {
+        let status = self.peers.entry(peer).or_default();
+        status.connected = connected;
+        if connected {
+            status.connected_at = Some(timestamp);
+        } else {
+            status.disconnected_at = Some(timestamp);
+        }
+    }
+
+    /// Initializes the node's state from the `Greetings` sent on every (re)connection
+    pub fn greet(&mut self, greetings: Greetings) {
+        self.decode_failures.record(
+            &self.local_connection.to_authority(),
+            "Greetings",
+            &greetings.skipped,
+        );
+        self.head_status = HeadStatus::from(greetings.head_status.as_str());
+        self.party = Some(hex::encode(&greetings.me));
+        self.version_compatible = self.hydra_versions.contains(&greetings.hydra_node_version);
+        if !self.version_compatible {
+            warn!(
+                "node {} runs hydra-node {}, which is outside the supported range; not placing games on it",
+                self.local_connection.to_authority(),
+                greetings.hydra_node_version
+            );
+        }
+        // Greetings come before anything we submit, ie. the script ref on HeadIsOpen
+        self.tx_builder.era = self
+            .configured_era
+            .unwrap_or_else(|| Era::for_hydra_version(&greetings.hydra_node_version));
+        self.hydra_node_version = Some(greetings.hydra_node_version);
+        self.utxo_cache.replace(greetings.snapshot_utxos);
+
+        // The snapshot tells us about any games that were running before we (re)connected
+        if self.head_status == HeadStatus::Open {
+            if let Some(utxos) = self.utxo_cache.utxos() {
+                self.reconcile(&utxos);
+            }
+        }
+    }
+
+    /// Rebuilds the tracked players from the head's UTxO set, and records how far our
+    /// state had drifted from it.
+    ///
+    /// Games we didn't know about (ie. after a restart) are tracked from now on; other
+    /// differences are only reported, since the head's snapshot may simply lag behind
+    /// transactions we've already seen.
+    pub fn reconcile(&mut self, utxos: &[UTxO]) {
+        let now = Utc::now();
+        let mut drift = Drift {
+            checked_at: Some(now.to_rfc3339()),
+            ..Default::default()
+        };
+
+        let mut games = HashMap::new();
+        for utxo in utxos {
+            if utxo.address.to_bech32().ok().as_deref() != Some(SCRIPT_ADDRESS) {
+                continue;
+            }
+            let game_state = match utxo
+                .inline_datum()
+                .map(|datum| GameState::try_from(datum.clone()))
+            {
+                Some(Ok(game_state)) => game_state,
+                // the script ref, or some unrelated output
+                _ => continue,
+            };
+            games.insert(game_state.owner.clone(), (utxo, game_state));
+        }
+
+        for player in self.players.iter() {
+            let tracked = match &player.utxo {
+                Some(utxo) => utxo,
+                // still waiting for the game to be created
+                None => continue,
+            };
+            match games.get(&player.pkh) {
+                Some((utxo, _)) if utxo.hash == tracked.hash && utxo.index == tracked.index => {}
+                Some(_) => drift.mismatched += 1,
+                None => drift.missing += 1,
+            }
+        }
+
+        for (owner, (utxo, game_state)) in games {
+            match self.players.iter_mut().find(|player| player.pkh == owner) {
+                Some(player) if player.utxo.is_none() => {
+                    player.utxo = Some(utxo.clone());
+                    player.utxo_time = now.timestamp() as u128;
+                    player.game_state = Some(game_state);
                 }
-                _ => false,
-            })
-            .collect::<Vec<
-                &PseudoTransactionOutput<
-                    PseudoPostAlonzoTransactionOutput<
-                        PseudoDatumOption<KeepRaw<PlutusData>>,
-                        PseudoScript<KeepRaw<NativeScript>>,
-                    >,
-                >,
-            >>();
-
-        if script_outputs.len() != 1 {
-            bail!("Invalid number of script outputs");
-        }
-
-        let script_output = script_outputs.first().unwrap();
-        match script_output {
-            PseudoTransactionOutput::PostAlonzo(output) => {
-                let datum = match output.datum_option.as_ref() {
-                    Some(PseudoDatumOption::Data(datum)) => datum,
-                    // If there's no datum, or a datum hash, it's an unrelated transaction
-                    _ => return Ok(()),
+                Some(_) => {}
+                None => {
+                    drift.untracked += 1;
+                    self.players.push(Player {
+                        pkh: owner,
+                        utxo: Some(utxo.clone()),
+                        utxo_time: now.timestamp() as u128,
+                        // We don't know when it started, so give it a full session from now
+                        started_at: now.timestamp() as u128,
+                        game_state: Some(game_state),
+                        end_token: None,
+                    });
                 }
-                .0
-                .raw_cbor();
+            }
+        }
 
-                let data = match decode::<PlutusData>(datum) {
-                    Ok(data) => data,
-                    Err(_) => bail!("Failed to deserialize datum"),
-                };
+        if drift.untracked + drift.missing + drift.mismatched > 0 {
+            warn!(
+                "node {} drifted from the head: {} untracked, {} missing, {} mismatched games",
+                self.local_connection.to_authority(),
+                drift.untracked,
+                drift.missing,
+                drift.mismatched
+            );
+        }
+        self.drift = drift;
+
+        let recovered = self.stats.reconcile_pending(utxos);
+        if recovered > 0 {
+            info!(
+                "confirmed {} pending transactions on {} from its UTxO set",
+                recovered,
+                self.local_connection.to_authority()
+            );
+        }
+    }
 
-                let game_state_result: Result<GameState> = data.try_into();
-                let game_state = game_state_result.context("invalid game state")?;
-
-                let player = match self
-                    .players
-                    .iter_mut()
-                    .find(|player| player.pkh == game_state.owner)
-                {
-                    Some(player) => player,
-                    None => {
-                        // We must have restarted, or the player was created through another control plane; create the player now
-                        warn!(
-                            "Unrecognized player, adding: {}",
-                            hex::encode(&game_state.owner)
-                        );
-                        self.players.push(Player {
-                            pkh: game_state.owner.clone(),
-                            utxo: None,
-                            game_state: Some(game_state.clone()),
-                            utxo_time: 0,
-                        });
-                        self.players
-                            .iter_mut()
-                            .find(|player| player.pkh == game_state.owner)
-                            .expect("Just added")
-                    }
+    /// Checks the script ref against the UTxO cache.
+    ///
+    /// Returns true if it has to be deployed, in which case the state is already moved to
+    /// `Deploying` and the caller must start `deploy_script_ref`; doing the transition here,
+    /// under the node lock, is what makes sure it's only deployed once. Failed deployments
+    /// are only retried when `retry_failed` is set, ie. when the head (re)opens.
+    ///
+    /// Nothing is decided while the node is replaying its history: a replayed HeadIsOpen
+    /// carries the head's initial UTxO set, which lacks a script ref deployed since. The
+    /// Greetings that ends the replay brings the current set, and is checked instead.
+    pub fn verify_script_ref(&mut self, retry_failed: bool) -> bool {
+        if self.head_status != HeadStatus::Open || !self.event_seq.is_greeted() {
+            return false;
+        }
+        let utxos = match self.utxo_cache.utxos() {
+            Some(utxos) => utxos,
+            None => return false,
+        };
+
+        match find_script_ref(&utxos) {
+            Some(utxo) => {
+                self.script_ref = ScriptRefState::Deployed {
+                    utxo: utxo.to_string(),
                 };
+                false
+            }
+            None => match self.script_ref {
+                ScriptRefState::Deploying { .. } => false,
+                ScriptRefState::Failed { .. } if !retry_failed => false,
+                _ => {
+                    self.script_ref = ScriptRefState::Deploying { utxo: None };
+                    true
+                }
+            },
+        }
+    }
 
-                // TODO: actually find the index
-                let utxo =
-                    UTxO::try_from_pallas(hex::encode(&transaction.tx_id).as_str(), 0, output)
-                        .context("invalid utxo")?;
-                let timestamp: u128 = transaction
-                    .timestamp
-                    .parse::<DateTime<Utc>>()
-                    .context("timestamp")?
-                    .timestamp() as u128;
-                player.utxo = Some(utxo);
-                player.utxo_time = timestamp;
-
-                let state_update =
-                    player.generate_state_update(transaction.cbor.len() as u64, game_state);
-
-                self.stats
-                    .pending_transactions
-                    .insert(transaction.tx_id, state_update);
-
-                Ok(())
+    /// Deploys the script ref to the node behind `handle`; see `verify_script_ref`
+    pub async fn deploy_script_ref(handle: NodeHandle) {
+        let client = handle.read().await.client();
+        let result = async {
+            let placement = client.placement.lock().await;
+            let utxos = client.utxos().await?;
+            let tx = client.tx_builder.build_script_ref(utxos)?;
+            let utxo = hex::encode(tx.tx_hash.0) + "#0";
+
+            if let ScriptRefState::Deploying { utxo: pending } =
+                &mut handle.write().await.script_ref
+            {
+                *pending = Some(utxo.clone());
+            }
+            let submission = client.submit(tx, WaitFor::Snapshot).await?;
+            drop(placement);
+            client.settle(submission).await?;
+            Ok::<String, Error>(utxo)
+        }
+        .await;
+
+        let mut node = handle.write().await;
+        match result {
+            Ok(utxo) => {
+                info!(
+                    "deployed script ref {} to {}",
+                    utxo,
+                    node.local_connection.to_authority()
+                );
+                node.script_ref = ScriptRefState::Deployed { utxo };
+            }
+            // the snapshot may have already shown us the script ref
+            Err(_) if matches!(node.script_ref, ScriptRefState::Deployed { .. }) => {}
+            Err(e) => {
+                warn!(
+                    "failed to deploy script ref to {}: {:?}",
+                    node.local_connection.to_authority(),
+                    e
+                );
+                node.script_ref = ScriptRefState::Failed {
+                    error: format!("{:#}", e),
+                };
             }
-            _ => bail!("Invalid output type"),
         }
     }
 
+    /// Records the snapshot just processed as the point the saved stats cover
+    pub fn snapshot_processed(&mut self) {
+        self.stats.snapshot_seq = self.event_seq.last();
+        self.stats.snapshot_head_id = self.event_seq.head_id().map(str::to_string);
+    }
+
     pub fn cleanup_players(&mut self) -> Vec<UTxO> {
         let mut to_remove = vec![];
         for (index, player) in self.players.iter().enumerate() {
-            if player.is_expired(Duration::from_secs(30)) {
-                let key = hex::encode(&player.pkh);
-                self.stats.total_kills += self.stats.kills.remove(&key).unwrap_or(0);
-                self.stats.total_items += self.stats.items.remove(&key).unwrap_or(0);
-                self.stats.total_secrets += self.stats.secrets.remove(&key).unwrap_or(0);
-                self.stats.total_play_time += self
-                    .stats
-                    .player_play_time
-                    .remove(&key)
-                    .unwrap_or_default()
-                    .iter()
-                    .sum::<u128>();
+            if self.expiry.is_expired(player) {
+                self.stats.finish_game(&hex::encode(&player.pkh));
                 to_remove.push(index);
             }
         }
@@ -442,6 +1049,107 @@ impl Node {
     }
 }
 
+/// Finds the UTxO `reference` ("<tx id>#<index>") points at
+fn find_utxo<'a>(utxos: &'a [UTxO], reference: &str) -> Option<&'a UTxO> {
+    utxos.iter().find(|utxo| utxo.to_string() == reference)
+}
+
+fn find_script_ref(utxos: &[UTxO]) -> Option<&UTxO> {
+    // Match on the validator's hash; other scripts may be parked at the same address
+    let script_hash = ScriptType::PlutusV2.hash(&hex::decode(SCRIPT_CBOR).unwrap());
+    find_reference_script(utxos, &script_hash)
+}
+
+fn is_script_output(output: &MultiEraOutput) -> bool {
+    let address = match output.address() {
+        Ok(address) => address,
+        Err(_) => return false,
+    };
+    // unwrapping here because it came from hydra, so it is valid
+    let address = address.to_bech32().unwrap();
+
+    address.as_str() == SCRIPT_ADDRESS
+}
+
+impl NodeClient {
+    pub async fn send(&self, message: String) -> Result<()> {
+        self.socket.send(message).await
+    }
+
+    /// Sends `tx`, built from `utxos()`, and applies it to the UTxO cache straight away, so
+    /// the next transaction built doesn't spend the same inputs even before the head has
+    /// accepted this one. The placement lock can be released once this returns.
+    pub async fn submit(
+        &self,
+        tx: BuiltTransaction,
+        wait_for: WaitFor,
+    ) -> Result<PendingSubmission> {
+        let tx_id = tx.tx_hash.0.to_vec();
+        let decoded = MultiEraTx::decode(&tx.tx_bytes.0).context("Failed to decode transaction")?;
+        self.utxo_cache.apply_transaction(&tx_id, &decoded);
+
+        let submission = self.submissions.track(tx_id.clone(), wait_for);
+        let sent = match NewTx::new(tx, self.tx_builder.era) {
+            Ok(message) => self.send(message.into()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            self.utxo_cache.discard(&tx_id);
+            return Err(e);
+        }
+        Ok(submission)
+    }
+
+    /// Waits for a transaction sent with `submit`; if it's rejected, or we give up on it,
+    /// it's taken back out of the UTxO cache (a TxValid turning up later puts it back)
+    pub async fn settle(&self, submission: PendingSubmission) -> Result<()> {
+        let tx_id = submission.tx_id().to_vec();
+        let result = submission.wait(self.submission_timeout).await;
+        if result.is_err() {
+            self.utxo_cache.discard(&tx_id);
+        }
+        result
+    }
+
+    /// The head's UTxO set, from the cache if we have it, otherwise fetched from the node
+    pub async fn utxos(&self) -> Result<Vec<UTxO>> {
+        match self.utxo_cache.utxos() {
+            Some(utxos) => Ok(utxos),
+            None => self.fetch_utxos().await.context("Failed to fetch utxos"),
+        }
+    }
+
+    /// The head's PlutusV2 cost model, from its protocol parameters
+    pub async fn fetch_cost_model(&self) -> Result<Vec<i64>> {
+        let request_url = self.local_connection.to_http_url() + "/protocol-parameters";
+        let response = reqwest::get(&request_url).await.context("http error")?;
+
+        let body = response.json::<Value>().await.context("http error")?;
+        body["costModels"]["PlutusV2"]
+            .as_array()
+            .context("Invalid PlutusV2 cost model")?
+            .iter()
+            .map(|cost| cost.as_i64().context("Invalid cost"))
+            .collect()
+    }
+
+    pub async fn fetch_utxos(&self) -> Result<Vec<UTxO>> {
+        let request_url = self.local_connection.to_http_url() + "/snapshot/utxo";
+        let response = reqwest::get(&request_url).await.context("http error")?;
+
+        let body = response.json::<Value>().await.context("http error")?;
+
+        let set = UtxoSet::try_from_json(&body)?;
+        self.decode_failures.record(
+            &self.local_connection.to_authority(),
+            "/snapshot/utxo",
+            &set.skipped,
+        );
+
+        Ok(set.utxos)
+    }
+}
+
 impl ConnectionInfo {
     fn from_config(value: &NodeConfig) -> Result<(Self, Self)> {
         Ok((
@@ -464,9 +1172,11 @@ impl ConnectionInfo {
         })
     }
 
+    /// Connects with history replay, so events missed while disconnected are delivered on
+    /// reconnect; already processed events are skipped by `seq` (see `EventSeq`).
     pub fn to_websocket_url(&self) -> String {
         let schema = if self.secure { "wss" } else { "ws" };
-        format!("{}://{}:{}?history=no", schema, self.host, self.port)
+        format!("{}://{}:{}?history=yes", schema, self.host, self.port)
     }
 
     pub fn to_http_url(&self) -> String {
@@ -490,24 +1200,78 @@ impl NodeStats {
 
             kills: HashMap::new(),
             total_kills: 0,
-            kills_leaderboard: vec![],
             items: HashMap::new(),
             total_items: 0,
-            items_leaderboard: vec![],
             secrets: HashMap::new(),
             total_secrets: 0,
-            secrets_leaderboard: vec![],
+            leaderboards: Leaderboards::default(),
             player_play_time: HashMap::new(),
             total_play_time: 0,
 
-            pending_transactions: HashMap::new(),
+            snapshot_seq: None,
+            snapshot_head_id: None,
+            orphaned_transactions: 0,
+            pending_transactions: 0,
+            pending: HashMap::new(),
         }
     }
 
     pub fn calculate_stats(&mut self, confirmed_txs: Vec<Vec<u8>>, stats_file: Option<String>) {
+        self.confirm(confirmed_txs);
+        self.save(stats_file);
+    }
+
+    pub fn add_pending(&mut self, tx_id: Vec<u8>, pending: PendingTransaction) {
+        self.pending.insert(tx_id, pending);
+        self.pending_transactions = self.pending.len();
+    }
+
+    /// Confirms pending transactions whose outputs are in `utxos`, ie. because we missed
+    /// the snapshot that confirmed them while disconnected, along with the earlier moves of
+    /// the same games, whose outputs those spent; returns how many there were
+    pub fn reconcile_pending(&mut self, utxos: &[UTxO]) -> usize {
+        let in_utxos = |tx_id: &Vec<u8>| utxos.iter().any(|utxo| &utxo.hash == tx_id);
+        // When each game last moved in a transaction that's known to be confirmed
+        let mut confirmed_moves: HashMap<&str, Instant> = HashMap::new();
+        for (_, pending) in self.pending.iter().filter(|(tx_id, _)| in_utxos(tx_id)) {
+            for update in pending.updates.iter() {
+                let seen_at = confirmed_moves
+                    .entry(update.player.as_str())
+                    .or_insert(pending.seen_at);
+                *seen_at = (*seen_at).max(pending.seen_at);
+            }
+        }
+
+        let mut confirmed: Vec<(Instant, Vec<u8>)> = self
+            .pending
+            .iter()
+            .filter(|(tx_id, pending)| {
+                in_utxos(tx_id)
+                    || pending.updates.iter().any(|update| {
+                        confirmed_moves
+                            .get(update.player.as_str())
+                            .is_some_and(|seen_at| pending.seen_at <= *seen_at)
+                    })
+            })
+            .map(|(tx_id, pending)| (pending.seen_at, tx_id.clone()))
+            .collect();
+        // Confirm in the order they happened, as play time is taken from the latest move
+        confirmed.sort();
+        let count = confirmed.len();
+        self.confirm(confirmed.into_iter().map(|(_, tx_id)| tx_id).collect());
+        count
+    }
+
+    fn confirm(&mut self, confirmed_txs: Vec<Vec<u8>>) {
         for tx_id in confirmed_txs {
-            match self.pending_transactions.remove(&tx_id) {
-                Some(state_change) => self.update_stats(state_change),
+            match self.pending.remove(&tx_id) {
+                Some(pending) => {
+                    self.transactions += 1;
+                    self.bytes += pending.bytes;
+                    for state_change in pending.updates {
+                        self.update_stats(state_change);
+                    }
+                }
 
                 None => debug!(
                     "Transaction in snapshot not found in stored transactions: {:?}",
@@ -515,8 +1279,17 @@ impl NodeStats {
                 ),
             }
         }
+        self.pending_transactions = self.pending.len();
+    }
+
+    pub fn save(&self, stats_file: Option<String>) {
         if let Some(stats_file) = stats_file {
-            let contents = match serde_json::to_string(&self) {
+            // Every player's leaderboard score is saved, but only shown as the boards
+            let contents = serde_json::to_value(self).and_then(|mut contents| {
+                contents["leaderboard_scores"] = serde_json::to_value(self.leaderboards.scores())?;
+                serde_json::to_string(&contents)
+            });
+            let contents = match contents {
                 Ok(contents) => contents,
                 Err(e) => {
                     warn!("failed to serialize stats {}", e);
@@ -540,105 +1313,82 @@ impl NodeStats {
         }
     }
 
-    fn update_stats(&mut self, state_change: StateUpdate) {
-        self.transactions += 1;
-        self.bytes += state_change.bytes;
-        let kills = self
-            .kills
-            .entry(state_change.player.clone())
-            .and_modify(|k| {
-                *k += if state_change.kills > 10000 {
-                    0
-                } else {
-                    state_change.kills
-                }
-            })
-            .or_insert(state_change.kills);
-        let mut min = *kills;
-        let mut found = false;
-        for entry in self.kills_leaderboard.iter_mut() {
-            if entry.0 == state_change.player {
-                if entry.1 < *kills {
-                    entry.1 = *kills;
-                }
-                found = true;
-                break;
-            }
-            if entry.1 < min {
-                min = entry.1;
-            }
-        }
-        if !found && (*kills > min || self.kills_leaderboard.len() < 10) {
-            self.kills_leaderboard
-                .push(LeaderboardEntry(state_change.player.clone(), *kills));
-        }
-        self.kills_leaderboard.sort();
-        self.kills_leaderboard.reverse();
-        self.kills_leaderboard.truncate(10);
-        let items = self
-            .items
-            .entry(state_change.player.clone())
-            .and_modify(|k| {
-                *k += if state_change.items > 10000 {
-                    0
-                } else {
-                    state_change.items
-                }
+    /// Drops transactions that have waited longer than `ttl` for a snapshot, which will
+    /// never come for them; returns how many were dropped
+    pub fn prune_pending(&mut self, ttl: Duration) -> usize {
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, pending| pending.seen_at.elapsed() <= ttl);
+        let orphaned = before - self.pending.len();
+        self.pending_transactions = self.pending.len();
+        self.orphaned_transactions += orphaned as u64;
+        orphaned
+    }
+
+    /// Folds a finished game's stats into the totals, returning them
+    pub fn finish_game(&mut self, player: &str) -> PlayerStats {
+        self.settle_pending(player);
+        let stats = PlayerStats {
+            kills: self.kills.remove(player).unwrap_or(0),
+            items: self.items.remove(player).unwrap_or(0),
+            secrets: self.secrets.remove(player).unwrap_or(0),
+            play_time: self
+                .player_play_time
+                .remove(player)
+                .unwrap_or_default()
+                .iter()
+                .sum::<u128>(),
+        };
+        self.total_kills += stats.kills;
+        self.total_items += stats.items;
+        self.total_secrets += stats.secrets;
+        self.total_play_time += stats.play_time;
+        self.leaderboards.record(Metric::GamesPlayed, player, 1);
+        stats
+    }
+
+    /// Applies the player's updates from transactions still waiting for a snapshot, in the
+    /// order they were seen. The game is over, so they can't be undone anymore; applied
+    /// later, they'd start a tally for a game that no longer exists. The transactions are
+    /// still confirmed as usual.
+    fn settle_pending(&mut self, player: &str) {
+        let mut pending: Vec<(Instant, Vec<StateUpdate>)> = self
+            .pending
+            .values_mut()
+            .map(|pending| {
+                let (updates, others) = pending
+                    .updates
+                    .drain(..)
+                    .partition(|update| update.player == player);
+                pending.updates = others;
+                (pending.seen_at, updates)
             })
-            .or_insert(state_change.items);
-        let mut min = *items;
-        let mut found = false;
-        for entry in self.items_leaderboard.iter_mut() {
-            if entry.0 == state_change.player {
-                if entry.1 < *items {
-                    entry.1 = *items;
-                }
-                found = true;
-                break;
-            }
-            if entry.1 < min {
-                min = entry.1;
-            }
+            .collect();
+        pending.sort_by_key(|(seen_at, _)| *seen_at);
+        for update in pending.into_iter().flat_map(|(_, updates)| updates) {
+            self.update_stats(update);
         }
-        if !found && (*items > min || self.items_leaderboard.len() < 10) {
-            self.items_leaderboard
-                .push(LeaderboardEntry(state_change.player.clone(), *items));
-        }
-        self.items_leaderboard.sort();
-        self.items_leaderboard.reverse();
-        self.items_leaderboard.truncate(10);
-        let secrets = self
-            .secrets
-            .entry(state_change.player.clone())
-            .and_modify(|k| {
-                *k += if state_change.secrets > 10000 {
-                    0
-                } else {
-                    state_change.secrets
-                }
-            })
-            .or_insert(state_change.secrets);
-        let mut min = *secrets;
-        let mut found = false;
-        for entry in self.secrets_leaderboard.iter_mut() {
-            if entry.0 == state_change.player {
-                if entry.1 < *secrets {
-                    entry.1 = *secrets;
-                }
-                found = true;
-                break;
-            }
-            if entry.1 < min {
-                min = entry.1;
-            }
-        }
-        if !found && (*secrets > min || self.secrets_leaderboard.len() < 10) {
-            self.secrets_leaderboard
-                .push(LeaderboardEntry(state_change.player.clone(), *secrets));
+    }
+
+    fn update_stats(&mut self, state_change: StateUpdate) {
+        let player = state_change.player.as_str();
+        let kills = Self::tally(&mut self.kills, player, state_change.kills);
+        let items = Self::tally(&mut self.items, player, state_change.items);
+        let secrets = Self::tally(&mut self.secrets, player, state_change.secrets);
+        self.leaderboards.record(Metric::Kills, player, kills);
+        self.leaderboards.record(Metric::Items, player, items);
+        self.leaderboards.record(Metric::Secrets, player, secrets);
+        self.leaderboards
+            .record(Metric::Bytes, player, state_change.bytes);
+        // The last level is the one being played, so it isn't finished yet
+        let finished_levels = state_change
+            .time
+            .split_last()
+            .map_or(&[][..], |(_, finished)| finished);
+        if let Some(fastest) = finished_levels.iter().filter(|time| **time > 0).min() {
+            self.leaderboards
+                .record(Metric::FastestLevel, player, *fastest as u64);
         }
-        self.secrets_leaderboard.sort();
-        self.secrets_leaderboard.reverse();
-        self.secrets_leaderboard.truncate(10);
 
         self.player_play_time
             .entry(state_change.player)
@@ -646,10 +1396,15 @@ impl NodeStats {
             .or_insert(state_change.time);
     }
 
-    pub fn join(&self, other: NodeStats, active_games: usize) -> NodeStats {
-        let mut pending_transactions = self.pending_transactions.clone();
-        pending_transactions.extend(other.pending_transactions);
+    /// Adds to a player's running total for their current game, returning the new total
+    fn tally(totals: &mut HashMap<String, u64>, player: &str, count: u64) -> u64 {
+        *totals
+            .entry(player.to_string())
+            .and_modify(|total| *total += if count > 10000 { 0 } else { count })
+            .or_insert(count)
+    }
 
+    pub fn join(&self, other: NodeStats, active_games: usize) -> NodeStats {
         let mut kills = self.kills.clone();
         kills.extend(other.kills);
         let mut items = self.items.clone();
@@ -670,42 +1425,476 @@ impl NodeStats {
 
             kills,
             total_kills: self.total_kills + other.total_kills,
-            kills_leaderboard: Self::merge_leaderboards(
-                &self.kills_leaderboard,
-                &other.kills_leaderboard,
-            ),
             items,
             total_items: self.total_items + other.total_items,
-            items_leaderboard: Self::merge_leaderboards(
-                &self.items_leaderboard,
-                &other.items_leaderboard,
-            ),
             secrets,
             total_secrets: self.total_secrets + other.total_secrets,
-            secrets_leaderboard: Self::merge_leaderboards(
-                &self.secrets_leaderboard,
-                &other.secrets_leaderboard,
-            ),
+            // merged across nodes by the `LeaderboardIndex` instead
+            leaderboards: Leaderboards::default(),
             player_play_time: play_time,
             total_play_time: self.total_play_time + other.total_play_time,
 
-            pending_transactions: HashMap::new(),
+            // sequence numbers are per node, so there's nothing meaningful to merge
+            snapshot_seq: None,
+            snapshot_head_id: None,
+            orphaned_transactions: self.orphaned_transactions + other.orphaned_transactions,
+            pending_transactions: self.pending_transactions + other.pending_transactions,
+            // Only the counts are reported across nodes
+            pending: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::{
+        codec::minicbor::encode,
+        crypto::hash::Hash,
+        ledger::addresses::{Network, ShelleyAddress, ShelleyDelegationPart, ShelleyPaymentPart},
+        txbuilder::{BuildBabbage, Input, Output, StagingTransaction},
+    };
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{model::leaderboard::Window, LeaderboardConfig, SocketConfig};
+
+    const ALICE: [u8; 28] = [0xa1; 28];
+    const BOB: [u8; 28] = [0xb0; 28];
+
+    fn test_node() -> Node {
+        let (writer, _) = mpsc::unbounded_channel();
+        let socket = HydraSocket::new(
+            "ws://127.0.0.1:4001",
+            "127.0.0.1:4001".to_string(),
+            &writer,
+            &SocketConfig::default(),
+        );
+        Node {
+            head_id: Some("head".to_string()),
+            head_status: HeadStatus::Open,
+            hydra_node_version: None,
+            party: None,
+            version_compatible: true,
+            stats: NodeStats::new(),
+            stats_file: None,
+            region: "test".to_string(),
+            max_players: 10,
+            persisted: false,
+            reserved: false,
+            online: socket.online.clone(),
+            health: socket.health.clone(),
+            drift: Drift::default(),
+            peers: HashMap::new(),
+            setup: None,
+            script_ref: ScriptRefState::Unknown,
+            local_connection: ConnectionInfo::from_url("http://127.0.0.1", 4001).unwrap(),
+            remote_connection: ConnectionInfo::from_url("http://127.0.0.1", 4001).unwrap(),
+            socket,
+            players: vec![],
+            tx_builder: TxBuilder::new(SecretKey::from([7u8; 32])),
+            configured_era: None,
+            placement: Arc::new(Mutex::new(())),
+            submissions: SubmissionTracker::default(),
+            utxo_cache: UtxoCache::default(),
+            decode_failures: DecodeFailures::default(),
+            event_seq: EventSeq::default(),
+            expected_peers: vec![],
+            hydra_versions: VersionRange::default(),
+            initialization_timeout: Duration::from_secs(60),
+            submission_timeout: Duration::from_secs(5),
+            expiry: ExpiryPolicy::default(),
+            pending_ttl: Duration::from_secs(600),
+        }
+    }
+
+    fn game_state(owner: &[u8], kills: u64) -> GameState {
+        let mut state = GameState::new(owner.to_vec(), vec![0xad; 28]);
+        state.player.total_stats.kill_count = kills;
+        state
+    }
+
+    fn game_output(state: GameState) -> Output {
+        let mut datum = vec![];
+        encode(PlutusData::from(state), &mut datum).unwrap();
+        Output::new(Address::from_bech32(SCRIPT_ADDRESS).unwrap(), 0).set_inline_datum(datum)
+    }
+
+    fn admin_output() -> Output {
+        let address = ShelleyAddress::new(
+            Network::Testnet,
+            ShelleyPaymentPart::Key(Hash::new([0xad; 28])),
+            ShelleyDelegationPart::Null,
+        );
+        Output::new(Address::Shelley(address), 10_000_000)
+    }
+
+    /// A TxValid for a transaction with the given outputs, as the head would report it
+    fn tx_valid(outputs: Vec<Output>) -> TxValid {
+        let mut tx = StagingTransaction::new()
+            .input(Input::new(Hash::new([0x11; 32]), 0))
+            .fee(0);
+        for output in outputs {
+            tx = tx.output(output);
+        }
+        let built = tx.build_babbage_raw().unwrap();
+        TxValid {
+            head_id: "head".to_string(),
+            seq: 1,
+            timestamp: "2024-08-01T12:00:00Z".to_string(),
+            cbor: built.tx_bytes.0.clone(),
+            descrption: String::new(),
+            tx_id: built.tx_hash.0.to_vec(),
+            tx_type: "Witnessed Tx BabbageEra".to_string(),
+        }
+    }
+
+    fn player<'a>(node: &'a Node, pkh: &[u8]) -> &'a Player {
+        node.players
+            .iter()
+            .find(|player| player.pkh == pkh)
+            .expect("player should be tracked")
+    }
+
+    #[test]
+    fn game_after_admin_output_keeps_its_index() {
+        let mut node = test_node();
+        let transaction = tx_valid(vec![admin_output(), game_output(game_state(&ALICE, 3))]);
+        let tx_id = transaction.tx_id.clone();
+
+        node.add_transaction(transaction).unwrap();
+
+        let utxo = player(&node, &ALICE).utxo.as_ref().unwrap();
+        assert_eq!(utxo.hash, tx_id);
+        assert_eq!(utxo.index, 1);
+
+        let pending = &node.stats.pending[&tx_id];
+        assert_eq!(pending.updates.len(), 1);
+        assert_eq!(pending.updates[0].player, hex::encode(ALICE));
+        assert_eq!(pending.updates[0].kills, 3);
+    }
+
+    #[test]
+    fn transaction_moving_two_games_updates_both() {
+        let mut node = test_node();
+        let transaction = tx_valid(vec![
+            admin_output(),
+            game_output(game_state(&ALICE, 3)),
+            game_output(game_state(&BOB, 5)),
+        ]);
+        let tx_id = transaction.tx_id.clone();
+        let bytes = transaction.cbor.len() as u64;
+
+        node.add_transaction(transaction).unwrap();
+
+        assert_eq!(node.players.len(), 2);
+        assert_eq!(player(&node, &ALICE).utxo.as_ref().unwrap().index, 1);
+        assert_eq!(player(&node, &BOB).utxo.as_ref().unwrap().index, 2);
+
+        let pending = &node.stats.pending[&tx_id];
+        assert_eq!(pending.bytes, bytes);
+        let updates = pending
+            .updates
+            .iter()
+            .map(|update| (update.player.clone(), update.kills, update.bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            updates,
+            vec![
+                (hex::encode(ALICE), 3, bytes / 2),
+                (hex::encode(BOB), 5, bytes / 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn later_move_reports_the_difference() {
+        let mut node = test_node();
+        node.add_transaction(tx_valid(vec![game_output(game_state(&ALICE, 3))]))
+            .unwrap();
+        let transaction = tx_valid(vec![admin_output(), game_output(game_state(&ALICE, 7))]);
+        let tx_id = transaction.tx_id.clone();
+
+        node.add_transaction(transaction).unwrap();
+
+        assert_eq!(node.players.len(), 1);
+        assert_eq!(player(&node, &ALICE).utxo.as_ref().unwrap().index, 1);
+        assert_eq!(node.stats.pending[&tx_id].updates[0].kills, 4);
+    }
+
+    #[test]
+    fn transaction_without_games_is_ignored() {
+        let mut node = test_node();
+        node.add_transaction(tx_valid(vec![admin_output()]))
+            .unwrap();
+
+        assert!(node.players.is_empty());
+        assert!(node.stats.pending.is_empty());
+    }
+
+    #[test]
+    fn finishing_a_game_settles_its_pending_moves() {
+        let mut node = test_node();
+        let transaction = tx_valid(vec![game_output(game_state(&ALICE, 3))]);
+        let tx_id = transaction.tx_id.clone();
+        node.add_transaction(transaction).unwrap();
+
+        let stats = node.stats.finish_game(&hex::encode(ALICE));
+        assert_eq!(stats.kills, 3);
+        assert_eq!(node.stats.total_kills, 3);
+
+        // The snapshot confirming the move comes after the game is over
+        node.stats.calculate_stats(vec![tx_id], None);
+        assert!(node.stats.kills.is_empty());
+        assert_eq!(node.stats.total_kills, 3);
+        assert_eq!(node.stats.transactions, 1);
+    }
+
+    #[test]
+    fn reconciling_a_later_move_confirms_the_earlier_ones() {
+        let mut node = test_node();
+        node.add_transaction(tx_valid(vec![game_output(game_state(&ALICE, 3))]))
+            .unwrap();
+        node.add_transaction(tx_valid(vec![
+            admin_output(),
+            game_output(game_state(&ALICE, 7)),
+        ]))
+        .unwrap();
+        node.add_transaction(tx_valid(vec![game_output(game_state(&BOB, 5))]))
+            .unwrap();
+        assert_eq!(node.stats.pending_transactions, 3);
+
+        // Only alice's latest move is left in the head; bob's is still on its way
+        let utxo = player(&node, &ALICE).utxo.clone().unwrap();
+        assert_eq!(node.stats.reconcile_pending(&[utxo]), 2);
+
+        assert_eq!(node.stats.pending_transactions, 1);
+        assert_eq!(node.stats.transactions, 2);
+        assert_eq!(node.stats.kills[&hex::encode(ALICE)], 7);
+        assert!(!node.stats.kills.contains_key(&hex::encode(BOB)));
+    }
+
+    #[test]
+    fn joined_stats_sum_pending_counts() {
+        let mut node = test_node();
+        node.add_transaction(tx_valid(vec![game_output(game_state(&ALICE, 3))]))
+            .unwrap();
+        let mut other = test_node();
+        other
+            .add_transaction(tx_valid(vec![game_output(game_state(&BOB, 5))]))
+            .unwrap();
+
+        let joined = NodeStats::new()
+            .join(node.stats.clone(), 1)
+            .join(other.stats.clone(), 1);
+        assert_eq!(joined.pending_transactions, 2);
+        assert!(joined.pending.is_empty());
+    }
+
+    #[test]
+    fn fastest_level_only_counts_finished_levels() {
+        let mut stats = NodeStats::new();
+        stats.leaderboards.configure(&LeaderboardConfig::default());
+        let update = |time: Vec<u128>| StateUpdate {
+            player: hex::encode(ALICE),
+            bytes: 0,
+            kills: 0,
+            items: 0,
+            secrets: 0,
+            time,
+        };
+
+        // Just started the first level
+        stats.update_stats(update(vec![35]));
+        assert!(stats
+            .leaderboards
+            .get(Window::AllTime, Metric::FastestLevel)
+            .is_none());
+
+        stats.update_stats(update(vec![900, 35]));
+        let board = stats
+            .leaderboards
+            .get(Window::AllTime, Metric::FastestLevel)
+            .unwrap();
+        assert_eq!(board.entries[0].score, 900);
+    }
+
+    /// A game UTxO as hydra renders it in JSON, for a game well into its first level
+    fn game_utxo_json(owner: &[u8], kills: u64) -> serde_json::Value {
+        let int = |int: i64| json!({ "int": int });
+        let constr = |constructor: u64, fields: Vec<serde_json::Value>| json!({ "constructor": constructor, "fields": fields });
+        let stats = |kills: u64| constr(0, vec![int(kills as i64), int(1), int(2)]);
+        let map_object = constr(
+            0,
+            vec![constr(0, vec![int(1056), int(-3616), int(0)]), int(87)],
+        );
+        json!({
+            "address": SCRIPT_ADDRESS,
+            "datum": null,
+            "inlineDatum": constr(0, vec![
+                // is_over: False
+                constr(0, vec![]),
+                constr(0, vec![json!({ "bytes": hex::encode(owner) })]),
+                constr(0, vec![json!({ "bytes": "ad".repeat(28) })]),
+                constr(0, vec![
+                    // player_state: Live
+                    constr(0, vec![]),
+                    map_object.clone(),
+                    stats(kills),
+                    stats(4),
+                    int(0),
+                ]),
+                json!({ "list": [map_object] }),
+                json!({ "list": [int(1234)] }),
+                // E1M1 on skill 2, not a demo
+                constr(0, vec![int(1), int(2), int(1), constr(0, vec![])]),
+            ]),
+            "referenceScript": null,
+            "value": { "lovelace": 0 },
+        })
+    }
+
+    #[test]
+    fn reconcile_picks_up_untracked_games() {
+        let mut node = test_node();
+        let utxos = UtxoSet::try_from_json(&json!({
+            "aa00000000000000000000000000000000000000000000000000000000000000#0":
+                game_utxo_json(&ALICE, 12),
+        }))
+        .unwrap();
+        assert!(utxos.skipped.is_empty(), "{:?}", utxos.skipped);
+
+        node.reconcile(&utxos.utxos);
+
+        assert_eq!(node.drift.untracked, 1);
+        let game_state = player(&node, &ALICE).game_state.clone().unwrap();
+        assert!(!game_state.is_over);
+        assert_eq!(game_state.player.total_stats.kill_count, 12);
+        assert_eq!(game_state.leveltime, vec![1234]);
+        assert_eq!(game_state.monsters.len(), 1);
+    }
+
+    #[test]
+    fn reconcile_checks_tracked_games_against_the_head() {
+        let mut node = test_node();
+        let utxo = |tx_id: &str| {
+            UTxO::try_from_value(&format!("{}#0", tx_id), &game_utxo_json(&ALICE, 12)).unwrap()
+        };
+        let tracked = "aa00000000000000000000000000000000000000000000000000000000000000";
+        let mut alice = Player::new(&Address::Shelley(ShelleyAddress::new(
+            Network::Testnet,
+            ShelleyPaymentPart::Key(Hash::new(ALICE)),
+            ShelleyDelegationPart::Null,
+        )))
+        .unwrap();
+        alice.utxo = Some(utxo(tracked));
+        node.players.push(alice);
+
+        node.reconcile(&[utxo(tracked)]);
+        assert_eq!(
+            node.drift.untracked + node.drift.missing + node.drift.mismatched,
+            0
+        );
+
+        node.reconcile(&[utxo(
+            "bb00000000000000000000000000000000000000000000000000000000000000",
+        )]);
+        assert_eq!(node.drift.mismatched, 1);
+
+        node.reconcile(&[]);
+        assert_eq!(node.drift.missing, 1);
+    }
+
+    #[test]
+    fn head_stuck_initializing_past_the_configured_timeout() {
+        let mut node = test_node();
+        node.initialization_timeout = Duration::from_secs(60);
+        let initializing = |secs_ago: i64| {
+            HeadIsInitializing::try_from(json!({
+                "headId": "head",
+                "parties": [{ "vkey": "a1".repeat(32) }],
+                "seq": 1,
+                "timestamp": (Utc::now() - chrono::Duration::seconds(secs_ago)).to_rfc3339(),
+            }))
+            .unwrap()
+        };
+
+        node.head_initializing(initializing(30));
+        assert!(!node.setup.as_ref().unwrap().is_stuck());
+
+        node.head_initializing(initializing(90));
+        assert_eq!(node.head_status, HeadStatus::Initializing);
+        assert!(node.setup.as_ref().unwrap().is_stuck());
+    }
+
+    fn head_is_open(seq: u64) -> HeadIsOpen {
+        HeadIsOpen::try_from(json!({
+            "headId": "head",
+            "seq": seq,
+            "timestamp": "2024-08-01T12:00:00Z",
+            "utxo": {},
+        }))
+        .unwrap()
+    }
+
+    fn greetings(snapshot_utxo: serde_json::Value) -> Greetings {
+        Greetings::try_from(json!({
+            "headStatus": "Open",
+            "hydraHeadId": "head",
+            "hydraNodeVersion": "0.19.0",
+            "me": { "vkey": "a1".repeat(32) },
+            "snapshotUtxo": snapshot_utxo,
+        }))
+        .unwrap()
+    }
+
+    /// Replays a HeadIsOpen and greets, as a node does on every connection, returning
+    /// whether either asked for the script ref to be deployed
+    fn reconnect(node: &mut Node, snapshot_utxo: serde_json::Value) -> bool {
+        node.connected();
+        let mut deploy = false;
+        if node.event_seq.track(1, Some("head")) {
+            node.head_opened(head_is_open(1));
         }
+        deploy |= node.verify_script_ref(true);
+        let greetings = greetings(snapshot_utxo);
+        node.event_seq.greeted(greetings.head_id.as_deref());
+        node.greet(greetings);
+        deploy |= node.verify_script_ref(true);
+        deploy
     }
 
-    pub fn merge_leaderboards(
-        left: &[LeaderboardEntry],
-        right: &[LeaderboardEntry],
-    ) -> Vec<LeaderboardEntry> {
-        let mut merged = vec![];
+    #[test]
+    fn replayed_head_is_open_doesnt_deploy_the_script_ref() {
+        let mut node = test_node();
+        node.head_status = HeadStatus::Initializing;
+        let script_ref = json!({
+            "3333333333333333333333333333333333333333333333333333333333333333#0": {
+                "address": SCRIPT_ADDRESS,
+                "value": { "lovelace": 0 },
+                "referenceScript": {
+                    "script": {
+                        "cborHex": format!("590391{}", SCRIPT_CBOR),
+                        "type": "PlutusScriptV2",
+                    },
+                },
+            },
+        });
+
+        assert!(!reconnect(&mut node, script_ref));
+
+        assert!(matches!(node.script_ref, ScriptRefState::Deployed { .. }));
+    }
 
-        merged.extend(left.iter().cloned());
-        merged.extend(right.iter().cloned());
+    #[test]
+    fn greetings_without_a_script_ref_deploy_it_once() {
+        let mut node = test_node();
+        node.head_status = HeadStatus::Initializing;
 
-        merged.sort();
-        merged.reverse();
-        merged.truncate(10);
+        assert!(reconnect(&mut node, json!({})));
+        assert!(matches!(node.script_ref, ScriptRefState::Deploying { .. }));
 
-        merged
+        // Reconnecting while it's on its way doesn't deploy another one
+        assert!(!reconnect(&mut node, json!({})));
     }
 }
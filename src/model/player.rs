@@ -10,7 +10,12 @@ pub struct Player {
     pub pkh: Vec<u8>,
     pub utxo: Option<UTxO>,
     pub utxo_time: u128,
+    /// When the game was requested, in seconds since the epoch
+    pub started_at: u128,
     pub game_state: Option<GameState>,
+    /// Proves a request to end the game comes from whoever created it; `None` for games
+    /// picked up from the head rather than placed by us, which can't be ended early
+    pub end_token: Option<String>,
 }
 
 impl Player {
@@ -24,7 +29,9 @@ impl Player {
             pkh,
             utxo: None,
             utxo_time: 0,
+            started_at: now(),
             game_state: None,
+            end_token: None,
         })
     }
 
@@ -71,17 +78,24 @@ impl Player {
         state_update
     }
 
-    pub fn is_expired(&self, duration: Duration) -> bool {
-        if self.utxo.is_none() {
-            // if we don't have a utxo yet, we haven't started playing
-            return false;
-        }
-
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Time went backwards...")
-            .as_secs() as u128;
+    /// How long since the player's last move, or `None` if they haven't started playing
+    pub fn idle_for(&self) -> Option<Duration> {
+        // if we don't have a utxo yet, we haven't started playing
+        self.utxo.as_ref()?;
+        Some(Duration::from_secs(
+            now().saturating_sub(self.utxo_time) as u64
+        ))
+    }
 
-        now - self.utxo_time > duration.as_secs() as u128
+    /// How long since the game was requested
+    pub fn session_length(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.started_at) as u64)
     }
 }
+
+fn now() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards...")
+        .as_secs() as u128
+}
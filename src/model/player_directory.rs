@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use pallas::{
+    crypto::key::ed25519::{PublicKey, Signature},
+    ledger::{addresses::Address, traverse::ComputeHash},
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use super::error::ControlPlaneError;
+
+const MIN_NAME_LENGTH: usize = 3;
+const MAX_NAME_LENGTH: usize = 24;
+/// What a player signs to claim a display name, followed by the name
+const NAME_CLAIM_PREFIX: &str = "hydra-doom display name: ";
+
+/// How a player is shown on leaderboards, in place of their payment key hash
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct PlayerIdentity {
+    /// The bech32 address the player last played with, if they've played since it was recorded
+    pub address: Option<String>,
+    pub display_name: Option<String>,
+}
+
+/// Proves a display name is claimed by the player themselves, rather than by anyone who
+/// knows their address
+#[derive(Clone, Debug, PartialEq, Deserialize, ToSchema)]
+pub struct NameProof {
+    /// The ed25519 verification key behind the player's address, hex encoded
+    pub public_key: String,
+    /// The key's signature over `hydra-doom display name: <display_name>`, hex encoded
+    pub signature: String,
+}
+
+impl NameProof {
+    fn verify(&self, pkh: &str, name: &str) -> bool {
+        let public_key = hex::decode(&self.public_key)
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key.as_slice()).ok())
+            .map(PublicKey::from);
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|signature| <[u8; 64]>::try_from(signature.as_slice()).ok())
+            .map(Signature::from);
+        match (public_key, signature) {
+            (Some(public_key), Some(signature)) => {
+                hex::encode(public_key.compute_hash().as_ref()) == pkh
+                    && public_key.verify(format!("{NAME_CLAIM_PREFIX}{name}"), &signature)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Who the players are, by payment key hash (hex encoded); saved to `players_file` by
+/// `save`, so names survive a restart
+#[derive(Clone, Default)]
+pub struct PlayerDirectory(Arc<Mutex<Directory>>);
+
+#[derive(Default)]
+struct Directory {
+    file: Option<PathBuf>,
+    players: HashMap<String, PlayerIdentity>,
+    /// Each player's display name, lower-cased as names are unique regardless of case, to
+    /// their payment key hash
+    names: HashMap<String, String>,
+    /// Whether `players` changed since it was last saved
+    changed: bool,
+}
+
+impl PlayerDirectory {
+    pub fn load(file: Option<PathBuf>) -> Result<Self> {
+        let players: HashMap<String, PlayerIdentity> = match &file {
+            Some(path) if path.exists() => serde_json::from_str(
+                &fs::read_to_string(path).context("unable to read players file")?,
+            )
+            .context("unable to parse players file")?,
+            _ => HashMap::new(),
+        };
+        let names = players
+            .iter()
+            .filter_map(|(pkh, identity)| {
+                let name = identity.display_name.as_ref()?;
+                Some((name.to_ascii_lowercase(), pkh.clone()))
+            })
+            .collect();
+        Ok(PlayerDirectory(Arc::new(Mutex::new(Directory {
+            file,
+            players,
+            names,
+            changed: false,
+        }))))
+    }
+
+    /// Checks that the player `pkh` may claim `name`: it must be valid, signed for with
+    /// `proof`, and not taken by another player
+    pub fn check_name(
+        &self,
+        pkh: &str,
+        name: &str,
+        proof: Option<&NameProof>,
+    ) -> Result<(), ControlPlaneError> {
+        if !is_valid_name(name) {
+            return Err(ControlPlaneError::InvalidDisplayName(name.to_string()));
+        }
+        if !proof.is_some_and(|proof| proof.verify(pkh, name)) {
+            return Err(ControlPlaneError::DisplayNameUnauthorized(name.to_string()));
+        }
+        let directory = self.0.lock().expect("player directory lock poisoned");
+        if directory
+            .find_by_name(name)
+            .is_some_and(|owner| owner != pkh)
+        {
+            return Err(ControlPlaneError::DisplayNameTaken(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Records the address a player is playing with, and gives them `display_name` if given,
+    /// which must have passed `check_name`; a player keeps their name until they claim
+    /// another. A name taken by someone else since it was checked is left to them.
+    pub fn register(&self, pkh: &str, address: &str, display_name: Option<&str>) {
+        let mut guard = self.0.lock().expect("player directory lock poisoned");
+        let directory = &mut *guard;
+        let display_name = display_name.filter(|name| {
+            let owner = directory.find_by_name(name);
+            if owner.is_some_and(|owner| owner != pkh) {
+                warn!("display name {} was taken before {} got it", name, pkh);
+                return false;
+            }
+            true
+        });
+
+        let identity = directory.players.entry(pkh.to_string()).or_default();
+        identity.address = Some(address.to_string());
+        if let Some(name) = display_name {
+            // claiming a new name frees the old one
+            if let Some(old) = identity.display_name.replace(name.to_string()) {
+                directory.names.remove(&old.to_ascii_lowercase());
+            }
+            directory
+                .names
+                .insert(name.to_ascii_lowercase(), pkh.to_string());
+        }
+        directory.changed = true;
+    }
+
+    /// Writes the players to `players_file` if they changed since the last time
+    pub async fn save(&self) {
+        let (file, contents) = {
+            let mut directory = self.0.lock().expect("player directory lock poisoned");
+            let Some(file) = directory.file.clone().filter(|_| directory.changed) else {
+                return;
+            };
+            directory.changed = false;
+            (file, serde_json::to_string(&directory.players))
+        };
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("failed to serialize players {}", e);
+                return;
+            }
+        };
+        if let Err(e) = tokio::fs::write(&file, contents).await {
+            warn!("failed to save players file {}", e);
+            self.0
+                .lock()
+                .expect("player directory lock poisoned")
+                .changed = true;
+        }
+    }
+
+    pub fn identify(&self, pkh: &str) -> PlayerIdentity {
+        let directory = self.0.lock().expect("player directory lock poisoned");
+        directory.players.get(pkh).cloned().unwrap_or_default()
+    }
+
+    /// Finds the payment key hash of a player given as a bech32 address, a display name or
+    /// the hash itself
+    pub fn resolve(&self, player: &str) -> Option<String> {
+        if let Ok(Address::Shelley(shelley)) = Address::from_bech32(player) {
+            return Some(hex::encode(shelley.payment().to_vec()));
+        }
+        let directory = self.0.lock().expect("player directory lock poisoned");
+        if let Some(pkh) = directory.find_by_name(player) {
+            return Some(pkh.to_string());
+        }
+        match hex::decode(player) {
+            Ok(pkh) if pkh.len() == 28 => Some(player.to_lowercase()),
+            _ => None,
+        }
+    }
+}
+
+impl Directory {
+    /// Names are unique regardless of case
+    fn find_by_name(&self, name: &str) -> Option<&str> {
+        self.names
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (MIN_NAME_LENGTH..=MAX_NAME_LENGTH).contains(&name.chars().count())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::crypto::key::ed25519::SecretKey;
+
+    use super::*;
+
+    fn pkh(key: &SecretKey) -> String {
+        hex::encode(key.public_key().compute_hash().as_ref())
+    }
+
+    fn proof(key: &SecretKey, name: &str) -> NameProof {
+        NameProof {
+            public_key: hex::encode(key.public_key().as_ref()),
+            signature: hex::encode(key.sign(format!("{NAME_CLAIM_PREFIX}{name}")).as_ref()),
+        }
+    }
+
+    #[test]
+    fn signed_name_is_claimed() {
+        let players = PlayerDirectory::default();
+        let key = SecretKey::from([1u8; 32]);
+        let pkh = pkh(&key);
+
+        players
+            .check_name(&pkh, "doomguy", Some(&proof(&key, "doomguy")))
+            .unwrap();
+        players.register(&pkh, "addr_test1", Some("doomguy"));
+
+        assert_eq!(players.resolve("DoomGuy"), Some(pkh.clone()));
+        assert_eq!(
+            players.identify(&pkh).display_name.as_deref(),
+            Some("doomguy")
+        );
+    }
+
+    #[test]
+    fn name_needs_the_players_own_signature() {
+        let players = PlayerDirectory::default();
+        let key = SecretKey::from([1u8; 32]);
+        let other = SecretKey::from([2u8; 32]);
+        let pkh = pkh(&key);
+
+        for proof in [
+            None,
+            Some(proof(&other, "doomguy")),
+            Some(proof(&key, "someone_else")),
+        ] {
+            assert!(matches!(
+                players.check_name(&pkh, "doomguy", proof.as_ref()),
+                Err(ControlPlaneError::DisplayNameUnauthorized(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn name_of_another_player_is_taken() {
+        let players = PlayerDirectory::default();
+        let key = SecretKey::from([1u8; 32]);
+        let other = SecretKey::from([2u8; 32]);
+        players.register(&pkh(&key), "addr_test1", Some("doomguy"));
+
+        assert!(matches!(
+            players.check_name(&pkh(&other), "DOOMGUY", Some(&proof(&other, "DOOMGUY"))),
+            Err(ControlPlaneError::DisplayNameTaken(_))
+        ));
+
+        // Checked before the first player got it, so it's skipped rather than stolen
+        players.register(&pkh(&other), "addr_test2", Some("doomguy"));
+        assert_eq!(players.identify(&pkh(&other)).display_name, None);
+        assert_eq!(players.resolve("doomguy"), Some(pkh(&key)));
+    }
+
+    #[test]
+    fn new_name_frees_the_old_one() {
+        let players = PlayerDirectory::default();
+        let key = SecretKey::from([1u8; 32]);
+        let other = SecretKey::from([2u8; 32]);
+        players.register(&pkh(&key), "addr_test1", Some("doomguy"));
+        players.register(&pkh(&key), "addr_test1", Some("DoomSlayer"));
+
+        assert_eq!(players.resolve("doomslayer"), Some(pkh(&key)));
+        assert_eq!(players.resolve("doomguy"), None);
+        players
+            .check_name(&pkh(&other), "doomguy", Some(&proof(&other, "doomguy")))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn names_are_found_after_a_restart() {
+        let file = std::env::temp_dir().join(format!("players-{}.json", std::process::id()));
+        let key = SecretKey::from([1u8; 32]);
+        let players = PlayerDirectory::load(Some(file.clone())).unwrap();
+        players.register(&pkh(&key), "addr_test1", Some("DoomGuy"));
+        players.save().await;
+
+        let restored = PlayerDirectory::load(Some(file.clone())).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(restored.resolve("doomguy"), Some(pkh(&key)));
+    }
+}
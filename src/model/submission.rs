@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use rocket::FromFormField;
+use serde::Deserialize;
+use tokio::{sync::oneshot, time::timeout};
+use utoipa::ToSchema;
+
+use super::error::ControlPlaneError;
+
+/// How far a submitted transaction must get before we report success
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromFormField, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitFor {
+    /// Return as soon as the transaction is sent to the node
+    #[field(value = "none")]
+    None,
+    /// Wait for the head to accept the transaction (`TxValid`)
+    #[field(value = "valid")]
+    Valid,
+    /// Wait for the transaction to be included in a confirmed snapshot
+    #[field(value = "snapshot")]
+    Snapshot,
+}
+
+struct Waiter {
+    wait_for: WaitFor,
+    sender: oneshot::Sender<Result<(), String>>,
+}
+
+/// Transactions we submitted and are waiting to hear back about, keyed by tx id
+#[derive(Clone, Default)]
+pub struct SubmissionTracker(Arc<Mutex<HashMap<Vec<u8>, Waiter>>>);
+
+pub struct PendingSubmission {
+    tx_id: Vec<u8>,
+    receiver: Option<oneshot::Receiver<Result<(), String>>>,
+    tracker: SubmissionTracker,
+}
+
+impl SubmissionTracker {
+    /// Starts tracking `tx_id`; must be called before the transaction is sent, so the
+    /// outcome can't arrive before anyone is listening for it.
+    pub fn track(&self, tx_id: Vec<u8>, wait_for: WaitFor) -> PendingSubmission {
+        let receiver = if wait_for == WaitFor::None {
+            None
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            self.0
+                .lock()
+                .expect("submission lock poisoned")
+                .insert(tx_id.clone(), Waiter { wait_for, sender });
+            Some(receiver)
+        };
+
+        PendingSubmission {
+            tx_id,
+            receiver,
+            tracker: self.clone(),
+        }
+    }
+
+    pub fn tx_valid(&self, tx_id: &[u8]) {
+        let mut waiters = self.0.lock().expect("submission lock poisoned");
+        if waiters
+            .get(tx_id)
+            .is_some_and(|waiter| waiter.wait_for == WaitFor::Valid)
+        {
+            let waiter = waiters.remove(tx_id).expect("just checked");
+            let _ = waiter.sender.send(Ok(()));
+        }
+    }
+
+    pub fn tx_invalid(&self, tx_id: &[u8], reason: &str) {
+        let mut waiters = self.0.lock().expect("submission lock poisoned");
+        if let Some(waiter) = waiters.remove(tx_id) {
+            let _ = waiter.sender.send(Err(reason.to_string()));
+        }
+    }
+
+    pub fn snapshot_confirmed(&self, confirmed_transactions: &[Vec<u8>]) {
+        let mut waiters = self.0.lock().expect("submission lock poisoned");
+        for tx_id in confirmed_transactions {
+            // A snapshot also implies the transaction was valid
+            if let Some(waiter) = waiters.remove(tx_id) {
+                let _ = waiter.sender.send(Ok(()));
+            }
+        }
+    }
+
+    fn forget(&self, tx_id: &[u8]) {
+        self.0
+            .lock()
+            .expect("submission lock poisoned")
+            .remove(tx_id);
+    }
+}
+
+impl PendingSubmission {
+    pub fn tx_id(&self) -> &[u8] {
+        &self.tx_id
+    }
+
+    /// Waits for the outcome of the transaction, giving up after `limit`
+    pub async fn wait(mut self, limit: Duration) -> Result<()> {
+        let receiver = match self.receiver.take() {
+            Some(receiver) => receiver,
+            None => return Ok(()),
+        };
+
+        match timeout(limit, receiver).await {
+            Ok(Ok(Ok(()))) => Ok(()),
+            Ok(Ok(Err(reason))) => Err(ControlPlaneError::TxRejected(reason).into()),
+            Ok(Err(_)) => Err(anyhow!("stopped tracking transaction")),
+            Err(_) => Err(ControlPlaneError::SubmissionTimeout(format!(
+                "after {:?} waiting for transaction {}",
+                limit,
+                hex::encode(&self.tx_id)
+            ))
+            .into()),
+        }
+    }
+}
+
+impl Drop for PendingSubmission {
+    fn drop(&mut self) {
+        // Don't leave a waiter behind for a transaction nobody is waiting on anymore
+        self.tracker.forget(&self.tx_id);
+    }
+}
@@ -4,19 +4,60 @@ use pallas::{
     crypto::{hash::Hash, key::ed25519::SecretKey},
     ledger::{
         addresses::{Address, ShelleyPaymentPart},
-        primitives::conway::{Constr, PlutusData},
+        primitives::conway::PlutusData,
         traverse::ComputeHash,
     },
-    txbuilder::{BuildBabbage, BuiltTransaction, Output, StagingTransaction},
+    txbuilder::{BuildBabbage, BuiltTransaction, ExUnits, Output, ScriptKind, StagingTransaction},
 };
 
-use super::{hydra::utxo::UTxO, player::Player};
-use crate::SCRIPT_ADDRESS;
+use serde::{Deserialize, Serialize};
+
+use super::{error::ControlPlaneError, game_state::constr, hydra::utxo::UTxO, player::Player};
+use crate::{parse_version, SCRIPT_ADDRESS, SCRIPT_CBOR};
 
 #[derive(Clone)]
 pub struct TxBuilder {
     pub admin_key: SecretKey,
     pub admin_pkh: Hash<28>,
+    pub era: Era,
+}
+
+/// The ledger era of the transactions a hydra-node accepts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Era {
+    #[default]
+    Babbage,
+    Conway,
+}
+
+impl Era {
+    /// Reads the era from a transaction envelope type, ie. "Witnessed Tx ConwayEra"
+    pub fn from_tx_type(tx_type: &str) -> Option<Era> {
+        if tx_type.ends_with("BabbageEra") {
+            Some(Era::Babbage)
+        } else if tx_type.ends_with("ConwayEra") {
+            Some(Era::Conway)
+        } else {
+            None
+        }
+    }
+
+    /// hydra-node moved its ledger to conway in 0.20.0; a version we can't read is assumed
+    /// to be a recent one
+    pub fn for_hydra_version(version: &str) -> Era {
+        match parse_version(version) {
+            Some(version) if version < (0, 20, 0) => Era::Babbage,
+            _ => Era::Conway,
+        }
+    }
+
+    pub fn tx_type(&self) -> &'static str {
+        match self {
+            Era::Babbage => "Witnessed Tx BabbageEra",
+            Era::Conway => "Witnessed Tx ConwayEra",
+        }
+    }
 }
 
 impl TxBuilder {
@@ -25,14 +66,25 @@ impl TxBuilder {
         TxBuilder {
             admin_key,
             admin_pkh,
+            era: Era::default(),
         }
     }
 
+    /// Builds the transaction body.
+    ///
+    /// pallas only ships a babbage builder, so this is always the babbage encoding; `era`
+    /// only decides the envelope it's submitted in (see `NewTx`). Everything we use (inputs,
+    /// reference inputs, outputs with inline datums or scripts, redeemers) is encoded the
+    /// same way in both eras, so the conway decoder reads back the very body that was
+    /// signed; the tests below check that for each transaction we build.
+    pub fn build(&self, tx: StagingTransaction) -> Result<BuiltTransaction> {
+        Ok(tx.build_babbage_raw()?)
+    }
+
     pub fn build_new_game_state(
         &self,
         player: &Player,
         utxos: Vec<UTxO>,
-        _expired_utxos: Vec<UTxO>,
         collateral_addr: Address,
     ) -> Result<(BuiltTransaction, Vec<u8>)> {
         if player.utxo.is_some() {
@@ -42,7 +94,7 @@ impl TxBuilder {
         let admin_utxos = self.find_admin_utxos(utxos);
 
         if admin_utxos.is_empty() {
-            bail!("No admin UTxOs found");
+            return Err(ControlPlaneError::NoAdminUtxo.into());
         };
 
         let input_utxo = admin_utxos.first().unwrap();
@@ -60,42 +112,92 @@ impl TxBuilder {
             .output(Output::new(script_address, 0).set_inline_datum(datum.clone()))
             // This is so the player has collateral, we can't clean this up unfortunately
             .output(Output::new(collateral_addr, 0))
-            .output(Output::new(
-                input_utxo.address.clone(),
-                input_utxo.value.get("lovelace").unwrap().to_owned(),
-            ))
+            // Hand back the admin's whole value, native assets included
+            .output(input_utxo.value.to_output(input_utxo.address.clone())?)
             .change_address(input_utxo.clone().address)
             .fee(0);
 
-        /* Skipping cleanup for now
-        if expired_utxos.len() > 0 {
+        let tx = self.build(tx_builder)?;
+        let signed_tx = tx
+            .sign(self.admin_key.clone().into())
+            .context("failed to sign tx")?;
+        Ok((signed_tx, datum))
+    }
+
+    pub fn build_script_ref(&self, utxos: Vec<UTxO>) -> Result<BuiltTransaction> {
+        let admin_utxos = self.find_admin_utxos(utxos);
+        if admin_utxos.is_empty() {
+            return Err(ControlPlaneError::NoAdminUtxo.into());
+        };
+
+        let input_utxo = admin_utxos.first().unwrap();
+
+        let script_address = Address::from_bech32(SCRIPT_ADDRESS).unwrap();
+
+        let bytes = hex::decode(SCRIPT_CBOR).unwrap();
+
+        let tx_builder = StagingTransaction::new()
+            .input(input_utxo.clone().into())
+            .output(Output::new(script_address, 0).set_inline_script(ScriptKind::PlutusV2, bytes))
+            .output(input_utxo.value.to_output(input_utxo.address.clone())?)
+            .fee(0);
+
+        let tx = self.build(tx_builder)?;
+        let signed_tx = tx
+            .sign(self.admin_key.clone().into())
+            .context("failed to sign tx")?;
+        Ok(signed_tx)
+    }
+
+    /// Ends the games in `game_utxos`, spending them back to the admin.
+    ///
+    /// The admin UTxO funds the transaction and doubles as collateral; `cost_model` is the
+    /// head's PlutusV2 cost model, needed for the script data hash.
+    pub fn build_end_games(
+        &self,
+        game_utxos: Vec<UTxO>,
+        utxos: Vec<UTxO>,
+        script_ref: UTxO,
+        cost_model: Vec<i64>,
+    ) -> Result<BuiltTransaction> {
+        let admin_utxos = self.find_admin_utxos(utxos);
+        let input_utxo = admin_utxos.first().ok_or(ControlPlaneError::NoAdminUtxo)?;
+
+        let mut value = input_utxo.value.clone();
+        let mut tx_builder = StagingTransaction::new();
+        for game_utxo in game_utxos {
+            value += game_utxo.value.clone();
             tx_builder = tx_builder
-                .reference_input(
-                    self.script_ref
-                        .as_ref()
-                        .expect("must have script ref by this point")
-                        .clone()
-                        .into(),
-                )
-                .collateral_input(input_utxo.clone().into());
-        }
-        for utxo in expired_utxos {
-            tx_builder = tx_builder.input(utxo.clone().into());
-            tx_builder = tx_builder.add_spend_redeemer(
-                utxo.into(),
-                TxBuilder::build_redeemer(),
-                Some(ExUnits {
-                    mem: 7000000,
-                    steps: 3000000000,
-                }),
-            );
+                .input(game_utxo.clone().into())
+                .add_spend_redeemer(
+                    game_utxo.into(),
+                    TxBuilder::build_end_redeemer(),
+                    Some(ExUnits {
+                        mem: 7000000,
+                        steps: 3000000000,
+                    }),
+                );
         }
-        */
-        let tx = tx_builder.build_babbage_raw()?;
+        let tx_builder = tx_builder
+            .input(input_utxo.clone().into())
+            .collateral_input(input_utxo.clone().into())
+            .reference_input(script_ref.into())
+            .disclosed_signer(self.admin_pkh)
+            .language_view(ScriptKind::PlutusV2, cost_model)
+            .output(value.to_output(input_utxo.address.clone())?)
+            .fee(0);
+
+        let tx = self.build(tx_builder)?;
         let signed_tx = tx
             .sign(self.admin_key.clone().into())
             .context("failed to sign tx")?;
-        Ok((signed_tx, datum))
+        Ok(signed_tx)
+    }
+
+    /// The token for ending the game created at `player_utxo` early: the admin's signature
+    /// over it, so it can't be made up by anyone else
+    pub fn end_token(&self, player_utxo: &str) -> String {
+        hex::encode(self.admin_key.sign(player_utxo.as_bytes()))
     }
 
     pub fn find_admin_utxos(&self, utxos: Vec<UTxO>) -> Vec<UTxO> {
@@ -112,16 +214,317 @@ impl TxBuilder {
             .collect()
     }
 
-    #[allow(dead_code)]
-    fn build_redeemer() -> Vec<u8> {
-        let mut datum: Vec<u8> = Vec::new();
-        let redeemer = PlutusData::Constr(Constr {
-            tag: 121,
-            any_constructor: Some(0),
-            fields: vec![],
-        });
-        encode(&redeemer, &mut datum).expect("Fatal error, this should never happen");
-
-        datum
+    /// The redeemer for spending a game UTxO to end the game.
+    ///
+    /// The validator takes one of two redeemers: constructor 0 with no fields, or
+    /// constructor 1 carrying a list of player commands (ten integers each); anything else
+    /// fails it. Ending a game replays no commands, so it's the former.
+    fn build_end_redeemer() -> Vec<u8> {
+        let mut redeemer_bytes: Vec<u8> = Vec::new();
+        let redeemer = constr(0, vec![]);
+        encode(&redeemer, &mut redeemer_bytes).expect("Fatal error, this should never happen");
+
+        redeemer_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::{
+        addresses::{Network, ShelleyAddress, ShelleyDelegationPart},
+        primitives::conway::{PseudoDatumOption, RedeemerTag},
+        traverse::{Era as LedgerEra, MultiEraTx},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::model::hydra::messages::new_tx::NewTx;
+
+    const ERAS: [Era; 2] = [Era::Babbage, Era::Conway];
+
+    fn tx_builder() -> TxBuilder {
+        TxBuilder::new(SecretKey::from([7u8; 32]))
+    }
+
+    fn key_address(pkh: Hash<28>) -> Address {
+        Address::Shelley(ShelleyAddress::new(
+            Network::Testnet,
+            ShelleyPaymentPart::Key(pkh),
+            ShelleyDelegationPart::Null,
+        ))
+    }
+
+    fn player_address() -> Address {
+        key_address(Hash::new([0xa1; 28]))
+    }
+
+    fn utxo(tx_id: &str, value: serde_json::Value) -> UTxO {
+        UTxO::try_from_value(tx_id, &value).unwrap()
+    }
+
+    fn admin_utxo(builder: &TxBuilder) -> UTxO {
+        utxo(
+            "1111111111111111111111111111111111111111111111111111111111111111#1",
+            json!({
+                "address": key_address(builder.admin_pkh).to_bech32().unwrap(),
+                "value": { "lovelace": 10_000_000 },
+            }),
+        )
+    }
+
+    fn game_utxo(tx_id: &str) -> UTxO {
+        utxo(
+            tx_id,
+            json!({ "address": SCRIPT_ADDRESS, "value": { "lovelace": 2_000_000 } }),
+        )
+    }
+
+    fn script_ref_utxo() -> UTxO {
+        utxo(
+            "3333333333333333333333333333333333333333333333333333333333333333#0",
+            json!({
+                "address": SCRIPT_ADDRESS,
+                "value": { "lovelace": 0 },
+                "referenceScript": {
+                    "script": { "cborHex": SCRIPT_CBOR, "type": "PlutusScriptV2" },
+                },
+            }),
+        )
+    }
+
+    fn end_games(builder: &TxBuilder, games: &[&str]) -> BuiltTransaction {
+        builder
+            .build_end_games(
+                games.iter().map(|tx_id| game_utxo(tx_id)).collect(),
+                vec![admin_utxo(builder)],
+                script_ref_utxo(),
+                vec![0; 175],
+            )
+            .unwrap()
+    }
+
+    /// The transaction bytes a node reads from the `NewTx` we'd submit `tx` to it in
+    fn submitted_cbor(tx: &BuiltTransaction, era: Era) -> Vec<u8> {
+        let message: String = NewTx::new(tx.clone(), era).unwrap().into();
+        let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(message["transaction"]["type"], era.tx_type());
+        assert_eq!(
+            message["transaction"]["txId"],
+            hex::encode(tx.tx_hash.0).as_str()
+        );
+        hex::decode(message["transaction"]["cborHex"].as_str().unwrap()).unwrap()
+    }
+
+    /// Decodes `cbor` with the ledger's decoder for `era`, checking the body reads back as
+    /// the one that was signed
+    fn decode<'a>(tx: &BuiltTransaction, cbor: &'a [u8], era: Era) -> MultiEraTx<'a> {
+        let ledger_era = match era {
+            Era::Babbage => LedgerEra::Babbage,
+            Era::Conway => LedgerEra::Conway,
+        };
+        let decoded = MultiEraTx::decode_for_era(ledger_era, cbor)
+            .unwrap_or_else(|e| panic!("{:?} should decode the transaction: {}", era, e));
+        assert_eq!(decoded.era(), ledger_era);
+        assert_eq!(decoded.hash().as_ref(), tx.tx_hash.0.as_slice());
+        decoded
+    }
+
+    #[test]
+    fn tx_types() {
+        assert_eq!(
+            Era::from_tx_type("Witnessed Tx BabbageEra"),
+            Some(Era::Babbage)
+        );
+        assert_eq!(
+            Era::from_tx_type("Witnessed Tx ConwayEra"),
+            Some(Era::Conway)
+        );
+        assert_eq!(Era::from_tx_type("Tx AlonzoEra"), None);
+        for era in ERAS {
+            assert_eq!(Era::from_tx_type(era.tx_type()), Some(era));
+        }
+    }
+
+    #[test]
+    fn new_game_decodes_in_both_eras() {
+        let builder = tx_builder();
+        let player = Player::new(&player_address()).unwrap();
+        let (tx, datum) = builder
+            .build_new_game_state(&player, vec![admin_utxo(&builder)], player_address())
+            .unwrap();
+
+        for era in ERAS {
+            let cbor = submitted_cbor(&tx, era);
+            let decoded = decode(&tx, &cbor, era);
+
+            assert_eq!(decoded.inputs().len(), 1);
+            let outputs = decoded.outputs();
+            assert_eq!(outputs.len(), 3);
+            assert_eq!(
+                outputs[0].address().unwrap().to_vec(),
+                Address::from_bech32(SCRIPT_ADDRESS).unwrap().to_vec()
+            );
+            match outputs[0].datum() {
+                Some(PseudoDatumOption::Data(data)) => assert_eq!(data.0.raw_cbor(), datum),
+                _ => panic!("game output should carry its state as an inline datum"),
+            }
+            assert_eq!(
+                outputs[1].address().unwrap().to_vec(),
+                player_address().to_vec()
+            );
+            assert_eq!(outputs[2].value().coin(), 10_000_000);
+        }
+    }
+
+    #[test]
+    fn script_ref_decodes_in_both_eras() {
+        let builder = tx_builder();
+        let tx = builder
+            .build_script_ref(vec![admin_utxo(&builder)])
+            .unwrap();
+
+        for era in ERAS {
+            let cbor = submitted_cbor(&tx, era);
+            let decoded = decode(&tx, &cbor, era);
+
+            let outputs = decoded.outputs();
+            assert_eq!(outputs.len(), 2);
+            let script = UTxO::try_from_pallas(&hex::encode(tx.tx_hash.0), 0, &outputs[0]).unwrap();
+            let script_hash = match Address::from_bech32(SCRIPT_ADDRESS).unwrap() {
+                Address::Shelley(address) => *address.payment().as_hash(),
+                _ => unreachable!(),
+            };
+            assert!(script.has_reference_script(&script_hash));
+        }
+    }
+
+    #[test]
+    fn end_games_decodes_in_both_eras() {
+        let builder = tx_builder();
+        let tx = end_games(
+            &builder,
+            &["2222222222222222222222222222222222222222222222222222222222222222#0"],
+        );
+
+        for era in ERAS {
+            let cbor = submitted_cbor(&tx, era);
+            let decoded = decode(&tx, &cbor, era);
+
+            assert_eq!(decoded.inputs().len(), 2);
+            assert_eq!(decoded.reference_inputs().len(), 1);
+            assert_eq!(decoded.collateral().len(), 1);
+            assert_eq!(decoded.redeemers().len(), 1);
+            assert_eq!(decoded.outputs().len(), 1);
+        }
+    }
+
+    #[test]
+    fn end_redeemer_is_the_validators_constructor_0() {
+        let redeemer: PlutusData =
+            pallas::codec::minicbor::decode(&TxBuilder::build_end_redeemer()).unwrap();
+
+        match redeemer {
+            PlutusData::Constr(constr) => {
+                assert_eq!(constr.tag, 121);
+                assert!(constr.fields.is_empty());
+            }
+            other => panic!("expected a constructor, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn end_games_spends_every_game_with_the_end_redeemer() {
+        let builder = tx_builder();
+        let games = [
+            "4444444444444444444444444444444444444444444444444444444444444444#0",
+            "2222222222222222222222222222222222222222222222222222222222222222#1",
+        ];
+        let tx = end_games(&builder, &games);
+
+        for era in ERAS {
+            let cbor = submitted_cbor(&tx, era);
+            let decoded = decode(&tx, &cbor, era);
+
+            // Inputs are ordered by reference: the admin's, then both games
+            let inputs: Vec<(u8, u64)> = decoded
+                .inputs()
+                .iter()
+                .map(|input| (input.hash()[0], input.index()))
+                .collect();
+            assert_eq!(inputs, vec![(0x11, 1), (0x22, 1), (0x44, 0)]);
+
+            let mut redeemers = decoded.redeemers();
+            redeemers.sort_by_key(|redeemer| redeemer.index());
+            assert_eq!(redeemers.len(), 2);
+            for (redeemer, index) in redeemers.iter().zip([1, 2]) {
+                assert_eq!(redeemer.tag(), RedeemerTag::Spend);
+                assert_eq!(redeemer.index(), index);
+                let mut data = Vec::new();
+                encode(redeemer.data(), &mut data).unwrap();
+                assert_eq!(data, TxBuilder::build_end_redeemer());
+                assert_eq!(redeemer.ex_units().mem, 7_000_000);
+                assert_eq!(redeemer.ex_units().steps, 3_000_000_000);
+            }
+        }
+    }
+
+    #[test]
+    fn end_games_pays_everything_back_to_the_admin() {
+        let builder = tx_builder();
+        let tx = end_games(
+            &builder,
+            &[
+                "2222222222222222222222222222222222222222222222222222222222222222#0",
+                "2222222222222222222222222222222222222222222222222222222222222222#1",
+            ],
+        );
+        let cbor = submitted_cbor(&tx, Era::Conway);
+        let decoded = decode(&tx, &cbor, Era::Conway);
+
+        let outputs = decoded.outputs();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(
+            outputs[0].address().unwrap().to_vec(),
+            key_address(builder.admin_pkh).to_vec()
+        );
+        assert_eq!(outputs[0].value().coin(), 14_000_000);
+        assert_eq!(decoded.fee(), Some(0));
+    }
+
+    #[test]
+    fn end_games_uses_the_admin_utxo_as_collateral_and_the_script_ref() {
+        let builder = tx_builder();
+        let tx = end_games(
+            &builder,
+            &["2222222222222222222222222222222222222222222222222222222222222222#0"],
+        );
+        let cbor = submitted_cbor(&tx, Era::Conway);
+        let decoded = decode(&tx, &cbor, Era::Conway);
+
+        let collateral = decoded.collateral();
+        assert_eq!(collateral.len(), 1);
+        assert_eq!(collateral[0].hash()[0], 0x11);
+        assert_eq!(collateral[0].index(), 1);
+        let reference_inputs = decoded.reference_inputs();
+        assert_eq!(reference_inputs.len(), 1);
+        assert_eq!(reference_inputs[0].hash()[0], 0x33);
+    }
+
+    #[test]
+    fn end_games_needs_an_admin_utxo() {
+        let builder = tx_builder();
+        let result = builder.build_end_games(
+            vec![game_utxo(
+                "2222222222222222222222222222222222222222222222222222222222222222#0",
+            )],
+            vec![],
+            script_ref_utxo(),
+            vec![0; 175],
+        );
+
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<ControlPlaneError>()),
+            Err(Ok(ControlPlaneError::NoAdminUtxo))
+        ));
     }
 }
@@ -0,0 +1,36 @@
+use rocket::{get, serde::json::Json, State};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    model::hydra::decode_failures::{DecodeFailures, FailureReport},
+    MyState,
+};
+
+#[derive(Serialize, ToSchema)]
+pub struct NodeDiagnostics {
+    pub id: Option<String>,
+    pub node: String,
+    #[schema(value_type = FailureReport)]
+    pub utxo_decode_failures: DecodeFailures,
+}
+
+/// Problems decoding what each node reported
+#[utoipa::path(
+    tag = "heads",
+    responses((status = 200, description = "Diagnostics for every node", body = Vec<NodeDiagnostics>))
+)]
+#[get("/diagnostics")]
+pub async fn diagnostics(state: &State<MyState>) -> Json<Vec<NodeDiagnostics>> {
+    let mut nodes = vec![];
+    for node in state.state.nodes.iter() {
+        let node = node.read().await;
+        nodes.push(NodeDiagnostics {
+            id: node.head_id.clone(),
+            node: node.local_connection.to_authority(),
+            utxo_decode_failures: node.decode_failures.clone(),
+        });
+    }
+
+    Json(nodes)
+}
@@ -0,0 +1,207 @@
+use std::convert::Infallible;
+
+use rocket::{
+    post,
+    request::{FromRequest, Outcome},
+    serde::json::Json,
+    Request, State,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use super::new_game::{place_game, NewGameResponse};
+use crate::{
+    model::{
+        error::{ControlPlaneError, ErrorBody},
+        idempotency::Recorder,
+        node::{Node, PlayerStats},
+        player_directory::NameProof,
+        submission::WaitFor,
+    },
+    MyState,
+};
+
+#[derive(Clone, Debug, PartialEq, Deserialize, ToSchema)]
+pub struct NewGameRequest {
+    /// The player's bech32 address
+    pub address: String,
+    /// Preferred region
+    pub region: Option<String>,
+    /// Place the game on a node reserved for cabinets
+    #[serde(default)]
+    pub reserved: bool,
+    pub client_version: Option<String>,
+    /// Preferred node, as returned in `ip` for an earlier game
+    pub node: Option<String>,
+    /// How far the game transaction must get before responding; defaults to `valid`
+    pub wait: Option<WaitFor>,
+    /// A name to show on leaderboards instead of the address; kept for later games
+    pub display_name: Option<String>,
+    /// Required with `display_name`
+    pub display_name_proof: Option<NameProof>,
+}
+
+/// The `Idempotency-Key` header, if the client sent one, and who sent it
+pub struct IdempotencyKey {
+    key: Option<String>,
+    requester: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IdempotencyKey {
+            key: request
+                .headers()
+                .get_one("Idempotency-Key")
+                .map(|key| key.to_string()),
+            requester: request
+                .client_ip()
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Places a new game for a player on the least busy available node.
+///
+/// Replaying a request with the same `Idempotency-Key` from the same client returns the
+/// original game rather than creating another one.
+#[utoipa::path(
+    tag = "games",
+    request_body = NewGameRequest,
+    params(("Idempotency-Key" = Option<String>, Header, description = "Makes the request safe to retry")),
+    responses(
+        (status = 200, description = "The game was created", body = NewGameResponse),
+        (status = 400, description = "`invalid_address` or `invalid_display_name`", body = ErrorBody),
+        (status = 403, description = "`display_name_unauthorized`", body = ErrorBody),
+        (status = 409, description = "`display_name_taken`", body = ErrorBody),
+        (status = 422, description = "`tx_rejected` or `idempotency_conflict`", body = ErrorBody),
+        (status = 500, description = "`internal`", body = ErrorBody),
+        (status = 503, description = "`no_capacity`, `no_admin_utxo`, `head_offline` or `script_ref_missing`", body = ErrorBody),
+        (status = 504, description = "`submission_timeout`", body = ErrorBody),
+    )
+)]
+#[post("/games", data = "<request>")]
+pub async fn create_game(
+    request: Json<NewGameRequest>,
+    idempotency_key: IdempotencyKey,
+    state: &State<MyState>,
+) -> Result<Json<NewGameResponse>, ControlPlaneError> {
+    let request = request.into_inner();
+    let place = |request: NewGameRequest, sent: Recorder<NewGameResponse>| async move {
+        debug!(
+            "placing game for {} (client version {:?})",
+            request.address, request.client_version
+        );
+        place_game(
+            state,
+            &request.address,
+            request.region.as_deref(),
+            request.reserved,
+            request.node.as_deref(),
+            request.display_name.as_deref(),
+            request.display_name_proof.as_ref(),
+            request.wait.unwrap_or(WaitFor::Valid),
+            &sent,
+        )
+        .await
+    };
+
+    match idempotency_key.key {
+        Some(key) => {
+            state
+                .games
+                .run(&idempotency_key.requester, &key, request, place)
+                .await
+        }
+        None => place(request, Recorder::default()).await,
+    }
+    .map(Json)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EndGameResponse {
+    /// The transaction that settled the game UTxO
+    tx_id: String,
+    stats: PlayerStats,
+}
+
+/// The bearer token from the `Authorization` header, if the client sent one
+pub struct BearerToken(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerToken {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(BearerToken(
+            request
+                .headers()
+                .get_one("Authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|token| token.trim().to_string()),
+        ))
+    }
+}
+
+/// Ends a game, settling its game UTxO and releasing the node slot right away.
+///
+/// `id` is the player's payment key hash, hex encoded. Only whoever created the game may
+/// end it, by sending the `end_token` they were given as a bearer token.
+#[utoipa::path(
+    tag = "games",
+    params(
+        ("id" = String, Path, description = "The player's payment key hash, hex encoded"),
+        ("Authorization" = String, Header, description = "`Bearer <end_token>`, as returned when the game was created"),
+        ("wait" = Option<String>, Query, description = "`none`, `valid` (default) or `snapshot`"),
+    ),
+    responses(
+        (status = 200, description = "The game was ended", body = EndGameResponse),
+        (status = 403, description = "`not_game_owner`", body = ErrorBody),
+        (status = 404, description = "`game_not_found`", body = ErrorBody),
+        (status = 422, description = "`tx_rejected`", body = ErrorBody),
+        (status = 500, description = "`internal`", body = ErrorBody),
+        (status = 503, description = "`no_admin_utxo`, `head_offline` or `script_ref_missing`", body = ErrorBody),
+        (status = 504, description = "`submission_timeout`", body = ErrorBody),
+    )
+)]
+#[post("/games/<id>/end?<wait>")]
+pub async fn end_game(
+    id: &str,
+    wait: Option<WaitFor>,
+    end_token: BearerToken,
+    state: &State<MyState>,
+) -> Result<Json<EndGameResponse>, ControlPlaneError> {
+    let pkh = hex::decode(id).map_err(|_| ControlPlaneError::GameNotFound(id.to_string()))?;
+    let end_token = end_token
+        .0
+        .ok_or_else(|| ControlPlaneError::NotGameOwner(id.to_string()))?;
+
+    let mut handle = None;
+    for node in state.state.nodes.iter() {
+        if node
+            .read()
+            .await
+            .players
+            .iter()
+            .any(|player| player.pkh == pkh)
+        {
+            handle = Some(node.clone());
+            break;
+        }
+    }
+    let handle = handle.ok_or_else(|| ControlPlaneError::GameNotFound(id.to_string()))?;
+
+    let (tx_id, stats) = Node::end_game(&handle, &pkh, &end_token, wait.unwrap_or(WaitFor::Valid))
+        .await
+        .map_err(|e| {
+            warn!("failed to end game {}: {:?}", id, e);
+            ControlPlaneError::from(e)
+        })?;
+
+    Ok(Json(EndGameResponse { tx_id, stats }))
+}
@@ -1,31 +1,34 @@
-use std::time::Duration;
-
 use rocket::{get, http::Status, serde::json::Json, State};
 
 use crate::{model::node::NodeStats, MyState};
 
+/// Statistics across every node
+#[utoipa::path(
+    tag = "global",
+    responses((status = 200, description = "Aggregated statistics", body = NodeStats))
+)]
 #[get("/global")]
 pub async fn global(state: &State<MyState>) -> Result<Json<NodeStats>, Status> {
-    let mut state_guard = state.state.state.write().await;
-    let stats = state_guard
-        .nodes
-        .iter_mut()
-        .fold(NodeStats::new(), |acc: NodeStats, node| {
-            if node.socket.online.load(std::sync::atomic::Ordering::SeqCst) {
-                node.stats.online_nodes = 1;
-                node.stats.offline_nodes = 0;
-            } else {
-                node.stats.offline_nodes = 1;
-                node.stats.online_nodes = 0;
-            }
-            acc.join(
-                node.clone().stats,
-                node.players
-                    .iter()
-                    .filter(|p| !p.is_expired(Duration::from_secs(5)) && p.utxo.is_some())
-                    .count(),
-            )
-        });
+    let mut stats = NodeStats::new();
+    for node in state.state.nodes.iter() {
+        let node = node.read().await;
+        let mut node_stats = node.stats.clone();
+        if node.socket.online.load(std::sync::atomic::Ordering::SeqCst) {
+            node_stats.online_nodes = 1;
+            node_stats.offline_nodes = 0;
+        } else {
+            node_stats.offline_nodes = 1;
+            node_stats.online_nodes = 0;
+        }
+        stats = stats.join(
+            node_stats,
+            node.players
+                .iter()
+                .filter(|p| node.expiry.is_active(p))
+                .count(),
+        );
+    }
+    stats.leaderboards = state.leaderboards.boards();
 
     Ok(Json(stats))
 }
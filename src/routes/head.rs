@@ -2,21 +2,21 @@ use rocket::{get, serde::json::Json, State};
 
 use crate::{model::node::Node, MyState};
 
+/// The nodes taking part in a head
+#[utoipa::path(
+    tag = "heads",
+    params(("head_id" = String, Path, description = "The hydra head id")),
+    responses((status = 200, description = "The head's nodes; empty if the head is unknown", body = Vec<Node>))
+)]
 #[get("/heads/<head_id>")]
 pub async fn head(state: &State<MyState>, head_id: &str) -> Json<Vec<Node>> {
-    let state_guard = state.state.state.read().await;
-    let nodes = state_guard
-        .nodes
-        .clone()
-        .iter()
-        .filter_map(|n| {
-            if n.head_id == Some(head_id.to_string()) {
-                Some(n.clone())
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<Node>>();
+    let mut nodes = vec![];
+    for node in state.state.nodes.iter() {
+        let node = node.read().await;
+        if node.head_id == Some(head_id.to_string()) {
+            nodes.push(node.clone());
+        }
+    }
 
     Json(nodes)
 }
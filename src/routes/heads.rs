@@ -2,15 +2,17 @@ use rocket::{get, serde::json::Json, State};
 
 use crate::{model::node::NodeSummary, MyState};
 
+/// Every node and its head
+#[utoipa::path(
+    tag = "heads",
+    responses((status = 200, description = "All nodes", body = Vec<NodeSummary>))
+)]
 #[get("/heads")]
 pub async fn heads(state: &State<MyState>) -> Json<Vec<NodeSummary>> {
-    let state_guard = state.state.state.read().await;
-    let nodes = state_guard
-        .nodes
-        .clone()
-        .iter()
-        .map(|s| NodeSummary(s.clone()))
-        .collect::<Vec<NodeSummary>>();
+    let mut nodes = vec![];
+    for node in state.state.nodes.iter() {
+        nodes.push(NodeSummary(node.read().await.clone()));
+    }
 
     Json(nodes)
 }
@@ -0,0 +1,160 @@
+use rocket::{get, serde::json::Json, State};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    model::{
+        error::{ControlPlaneError, ErrorBody},
+        leaderboard::{Metric, Window},
+        player_directory::PlayerIdentity,
+    },
+    MyState,
+};
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Serialize, ToSchema)]
+pub struct RankedEntry {
+    /// 1 is the best
+    rank: usize,
+    player: PlayerIdentity,
+    score: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LeaderboardPage {
+    metric: Metric,
+    window: Window,
+    /// When the window started, in seconds since the epoch
+    since: u64,
+    /// How many players have a score, across every page
+    total: usize,
+    entries: Vec<RankedEntry>,
+}
+
+/// A page of a leaderboard, merged across every node (or just those in `region`)
+///
+/// Boards are merged every sweep (`sweep_interval_secs`), so a finished game takes up to
+/// that long to show.
+#[utoipa::path(
+    tag = "leaderboards",
+    params(
+        ("metric" = Metric, Path, description = "What players are ranked by"),
+        ("window" = Option<Window>, Query, description = "Defaults to `all_time`"),
+        ("region" = Option<String>, Query, description = "Only count games on nodes in this region"),
+        ("limit" = Option<usize>, Query, description = "Defaults to 10, at most 100"),
+        ("offset" = Option<usize>, Query, description = "How many entries to skip"),
+    ),
+    responses(
+        (status = 200, description = "The leaderboard", body = LeaderboardPage),
+        (status = 404, description = "No such metric"),
+    )
+)]
+#[get("/leaderboards/<metric>?<window>&<region>&<limit>&<offset>")]
+pub async fn leaderboard(
+    metric: Metric,
+    window: Option<Window>,
+    region: Option<&str>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    state: &State<MyState>,
+) -> Json<LeaderboardPage> {
+    let window = window.unwrap_or(Window::AllTime);
+    let standings = state
+        .leaderboards
+        .standings(region, window, metric)
+        .unwrap_or_default();
+
+    let offset = offset.unwrap_or(0);
+    let entries = standings
+        .entries
+        .iter()
+        .enumerate()
+        .skip(offset)
+        .take(limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT))
+        .map(|(index, entry)| RankedEntry {
+            rank: index + 1,
+            player: state.players.identify(&entry.player),
+            score: entry.score,
+        })
+        .collect();
+
+    Json(LeaderboardPage {
+        metric,
+        window,
+        since: standings.since,
+        total: standings.entries.len(),
+        entries,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Rank {
+    metric: Metric,
+    /// 1 is the best
+    rank: usize,
+    score: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PlayerRanks {
+    player: PlayerIdentity,
+    window: Window,
+    /// Only the metrics the player has a score for
+    ranks: Vec<Rank>,
+}
+
+/// Where a player stands on each leaderboard
+///
+/// `player` is a bech32 address, a display name or a payment key hash (hex encoded).
+#[utoipa::path(
+    tag = "leaderboards",
+    params(
+        ("player" = String, Path, description = "A bech32 address, display name or payment key hash"),
+        ("metric" = Option<Metric>, Query, description = "Only this leaderboard; defaults to all of them"),
+        ("window" = Option<Window>, Query, description = "Defaults to `all_time`"),
+        ("region" = Option<String>, Query, description = "Only count games on nodes in this region"),
+    ),
+    responses(
+        (status = 200, description = "The player's ranks", body = PlayerRanks),
+        (status = 404, description = "`player_not_found`", body = ErrorBody),
+    )
+)]
+#[get("/players/<player>/rank?<metric>&<window>&<region>")]
+pub async fn player_rank(
+    player: &str,
+    metric: Option<Metric>,
+    window: Option<Window>,
+    region: Option<&str>,
+    state: &State<MyState>,
+) -> Result<Json<PlayerRanks>, ControlPlaneError> {
+    let pkh = state
+        .players
+        .resolve(player)
+        .ok_or_else(|| ControlPlaneError::PlayerNotFound(player.to_string()))?;
+    let window = window.unwrap_or(Window::AllTime);
+
+    let metrics = match metric {
+        Some(metric) => vec![metric],
+        None => Metric::all(),
+    };
+    let ranks = metrics
+        .into_iter()
+        .filter_map(|metric| {
+            let standings = state.leaderboards.standings(region, window, metric)?;
+            let index = standings.position(&pkh)?;
+            Some(Rank {
+                metric,
+                rank: index + 1,
+                score: standings.entries[index].score,
+            })
+        })
+        .collect();
+
+    Ok(Json(PlayerRanks {
+        player: state.players.identify(&pkh),
+        window,
+        ranks,
+    }))
+}
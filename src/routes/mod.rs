@@ -1,4 +1,25 @@
+use rocket::{routes, Route};
+
+pub mod diagnostics;
+pub mod games;
 pub mod global;
 pub mod head;
 pub mod heads;
+pub mod leaderboards;
 pub mod new_game;
+pub mod openapi;
+
+/// Every API route; each must also be listed in `openapi::ApiDoc`
+pub fn all() -> Vec<Route> {
+    routes![
+        new_game::new_game,
+        games::create_game,
+        games::end_game,
+        heads::heads,
+        head::head,
+        global::global,
+        leaderboards::leaderboard,
+        leaderboards::player_rank,
+        diagnostics::diagnostics
+    ]
+}
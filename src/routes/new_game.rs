@@ -1,88 +1,170 @@
-use std::sync::atomic::Ordering;
-
 use itertools::Itertools;
 use pallas::ledger::addresses::Address;
-use rocket::{get, http::Status, serde::json::Json, State};
+use rocket::{get, serde::json::Json, State};
 use serde::Serialize;
-use std::fmt::Write;
 use tracing::warn;
+use utoipa::ToSchema;
 
 use crate::{
-    model::{node::Node, player::Player},
+    model::{
+        error::{ControlPlaneError, ErrorBody},
+        hydra::state::NodeHandle,
+        idempotency::Recorder,
+        node::{NewGame, Node, ScriptRefState},
+        player::Player,
+        player_directory::NameProof,
+        submission::WaitFor,
+    },
     MyState,
 };
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize, ToSchema)]
 pub struct NewGameResponse {
     ip: String,
     script_ref: String,
     admin_pkh: String,
     player_utxo: String,
     player_utxo_datum_hex: String,
+    /// Send as `Authorization: Bearer <end_token>` to `POST /games/{id}/end`
+    end_token: String,
 }
 
-#[get("/new_game?<address>&<region>&<reserved>")]
+/// Places a new game for a player on the least busy available node.
+///
+/// Prefer `POST /games`, which can't be triggered by a prefetch and is safe to retry.
+#[utoipa::path(
+    tag = "games",
+    params(
+        ("address" = String, Query, description = "The player's bech32 address"),
+        ("region" = Option<String>, Query, description = "Preferred region"),
+        ("reserved" = bool, Query, description = "Place the game on a node reserved for cabinets"),
+        ("wait" = Option<String>, Query, description = "`none`, `valid` (default) or `snapshot`"),
+    ),
+    responses(
+        (status = 200, description = "The game was created", body = NewGameResponse),
+        (status = 400, description = "`invalid_address`", body = ErrorBody),
+        (status = 422, description = "`tx_rejected`", body = ErrorBody),
+        (status = 500, description = "`internal`", body = ErrorBody),
+        (status = 503, description = "`no_capacity`, `no_admin_utxo`, `head_offline` or `script_ref_missing`", body = ErrorBody),
+        (status = 504, description = "`submission_timeout`", body = ErrorBody),
+    )
+)]
+#[get("/new_game?<address>&<region>&<reserved>&<wait>")]
 pub async fn new_game(
     address: &str,
     region: Option<&str>,
     reserved: bool,
+    wait: Option<WaitFor>,
     state: &State<MyState>,
-) -> Result<Json<NewGameResponse>, Status> {
-    let mut state_guard = state.state.state.write().await;
-    let node: &mut Node = state_guard
-        .nodes
-        .iter_mut()
-        // Only direct games to online games
-        .filter(|n| n.socket.online.load(Ordering::SeqCst))
+) -> Result<Json<NewGameResponse>, ControlPlaneError> {
+    place_game(
+        state,
+        address,
+        region,
+        reserved,
+        None,
+        None,
+        None,
+        wait.unwrap_or(WaitFor::Valid),
+        &Recorder::default(),
+    )
+    .await
+    .map(Json)
+}
+
+/// Places a game on the least busy available node, preferring `preferred_node` (a node's
+/// public authority, as returned in `ip`) and then the player's region
+///
+/// Once the game is placed, the player's address (and `display_name`, if given and signed
+/// for with `name_proof`) is recorded for the leaderboards. The response is recorded in
+/// `sent` once the game transaction is sent.
+#[allow(clippy::too_many_arguments)]
+pub async fn place_game(
+    state: &MyState,
+    address: &str,
+    region: Option<&str>,
+    reserved: bool,
+    preferred_node: Option<&str>,
+    display_name: Option<&str>,
+    name_proof: Option<&NameProof>,
+    wait: WaitFor,
+    sent: &Recorder<NewGameResponse>,
+) -> Result<NewGameResponse, ControlPlaneError> {
+    let mut candidates = vec![];
+    for handle in state.state.nodes.iter() {
+        let node = handle.read().await;
+        // Only direct games to online, compatible nodes
         // Reserve some machines for the on-site cabinets
-        .filter(|n| reserved == n.reserved)
-        .sorted_by_key(|n| {
-            let same_region = if region == Some(n.region.as_str()) {
+        if node.is_available() && reserved == node.reserved {
+            let same_region = if region == Some(node.region.as_str()) {
                 1
             } else {
                 10
             };
-            // give preference to the users preferred region
-            (n.players.len() + 1) * same_region
-        })
+            // give preference to the users preferred node, then their region
+            let preferred = preferred_node == Some(node.remote_connection.to_authority().as_str());
+            candidates.push((
+                (!preferred, (node.players.len() + 1) * same_region),
+                handle.clone(),
+            ));
+        }
+    }
+    let handle: NodeHandle = candidates
+        .into_iter()
+        .sorted_by_key(|(weight, _)| *weight)
+        .map(|(_, handle)| handle)
         .next() // Get the first with the fewest players
         .ok_or_else(|| {
             warn!("No nodes available");
-            Status::ServiceUnavailable
+            ControlPlaneError::NoCapacity
         })?;
 
-    let addr = Address::from_bech32(address).map_err(|_| Status::BadRequest)?;
-
-    let player = Player::new(&addr).map_err(|_| Status::BadRequest)?;
-    let (player_utxo, player_utxo_datum_hex) =
-        node.add_player(player, addr).await.map_err(|e| {
-            warn!("failed to add player {:?}", e);
-            Status::InternalServerError
-        })?;
+    let addr = Address::from_bech32(address)
+        .map_err(|_| ControlPlaneError::InvalidAddress(address.to_string()))?;
 
-    let xs = node.find_script_ref().await;
-    let script_ref = match xs {
-        None => node.create_script_ref().await.map_err(|e| {
-            warn!("failed to commit script {:?}", e);
-            Status::InternalServerError
-        }),
-        Some(x) => {
-            let mut hex_hash = String::with_capacity(x.hash.len() * 2);
-            for byte in &x.hash {
-                write!(&mut hex_hash, "{:02x}", byte).unwrap();
-            }
-            Ok(format!("{}#{}", hex_hash, x.index))
+    let player =
+        Player::new(&addr).map_err(|_| ControlPlaneError::InvalidAddress(address.to_string()))?;
+    let pkh = hex::encode(&player.pkh);
+    if let Some(name) = display_name {
+        state.players.check_name(&pkh, name, name_proof)?;
+    }
+    let script_ref = match &handle.read().await.script_ref {
+        ScriptRefState::Deployed { utxo } => utxo.clone(),
+        // The node was available a moment ago, so the script ref must have just gone missing
+        script_ref => {
+            warn!("script ref not available: {:?}", script_ref);
+            return Err(ControlPlaneError::ScriptRefMissing);
         }
-    }?;
+    };
 
+    let (ip, admin_pkh) = {
+        let node = handle.read().await;
+        (
+            node.remote_connection.to_authority(),
+            node.tx_builder.admin_pkh.to_string(),
+        )
+    };
     // TODO: move this to the frontend to lookup
     // TODO: This is hard coded because our offline nodes have them in the initial-utxo
     //
-    Ok(Json(NewGameResponse {
-        ip: node.remote_connection.to_authority(),
-        script_ref,
-        admin_pkh: node.tx_builder.admin_pkh.to_string(),
-        player_utxo,
-        player_utxo_datum_hex,
-    }))
+    let response = |game: &NewGame| NewGameResponse {
+        ip: ip.clone(),
+        script_ref: script_ref.clone(),
+        admin_pkh: admin_pkh.clone(),
+        player_utxo: game.player_utxo.clone(),
+        player_utxo_datum_hex: game.player_utxo_datum_hex.clone(),
+        end_token: game.end_token.clone(),
+    };
+
+    let game = Node::add_player(&handle, player, addr, wait, |game| {
+        sent.record(response(game))
+    })
+    .await
+    .map_err(|e| {
+        warn!("failed to add player {:?}", e);
+        ControlPlaneError::from(e)
+    })?;
+
+    state.players.register(&pkh, address, display_name);
+    Ok(response(&game))
 }
@@ -0,0 +1,116 @@
+use utoipa::OpenApi;
+
+use super::{diagnostics, games, global, head, heads, leaderboards, new_game};
+use crate::model::{
+    error::ErrorBody,
+    hydra::{
+        decode_failures::{FailureReport, RecordedFailure},
+        head_setup::HeadSetupReport,
+        hydra_socket::{HealthReport, HealthState},
+    },
+    leaderboard::{Leaderboard, LeaderboardEntry, Metric, Window},
+    node::{
+        Drift, HeadStatus, Node, NodeStats, NodeSummary, PeerStatus, PlayerStats, ScriptRefState,
+    },
+    player_directory::{NameProof, PlayerIdentity},
+    submission::WaitFor,
+};
+
+/// The API contract, generated from the routes themselves; served at `/openapi.json`
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Hydra Doom Control Plane"),
+    paths(
+        global::global,
+        heads::heads,
+        head::head,
+        new_game::new_game,
+        games::create_game,
+        games::end_game,
+        leaderboards::leaderboard,
+        leaderboards::player_rank,
+        diagnostics::diagnostics
+    ),
+    components(schemas(
+        Node,
+        NodeSummary,
+        NodeStats,
+        HeadStatus,
+        PeerStatus,
+        Drift,
+        ScriptRefState,
+        HealthState,
+        HealthReport,
+        HeadSetupReport,
+        FailureReport,
+        RecordedFailure,
+        new_game::NewGameResponse,
+        games::NewGameRequest,
+        games::EndGameResponse,
+        PlayerStats,
+        Leaderboard,
+        LeaderboardEntry,
+        Metric,
+        Window,
+        PlayerIdentity,
+        NameProof,
+        leaderboards::LeaderboardPage,
+        leaderboards::RankedEntry,
+        leaderboards::PlayerRanks,
+        leaderboards::Rank,
+        WaitFor,
+        diagnostics::NodeDiagnostics,
+        ErrorBody
+    )),
+    tags(
+        (name = "global", description = "Status of the entire cluster"),
+        (name = "heads", description = "Status of the hydra heads"),
+        (name = "games", description = "Individual games"),
+        (name = "leaderboards", description = "Player rankings")
+    )
+)]
+pub struct ApiDoc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: [&str; 8] = [
+        "get", "put", "post", "delete", "options", "head", "patch", "trace",
+    ];
+
+    /// A mounted route's path as it appears in the spec, ie. `/games/<id>/end?<wait>` is
+    /// `/games/{id}/end`
+    fn spec_path(path: &str) -> String {
+        path.replace('<', "{").replace('>', "}")
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let routes = crate::routes::all();
+        for route in routes.iter() {
+            let path = spec_path(&route.uri.path().to_string());
+            let method = route.method.as_str().to_lowercase();
+            assert!(
+                spec["paths"][&path][&method].is_object(),
+                "{} {} is mounted but missing from the OpenAPI spec",
+                method,
+                path
+            );
+        }
+
+        let documented = spec["paths"]
+            .as_object()
+            .unwrap()
+            .values()
+            .flat_map(|item| item.as_object().unwrap().keys())
+            .filter(|key| METHODS.contains(&key.as_str()))
+            .count();
+        assert_eq!(
+            documented,
+            routes.len(),
+            "the spec documents unmounted routes"
+        );
+    }
+}